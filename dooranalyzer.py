@@ -2,68 +2,210 @@ from threading import Thread
 from doorscreen import *
 from doorcam import *
 import time
+import datetime
 from logging import getLogger
+from doorconfig import time_in_window
 
 ANALYZER_DECODE_FLAGS = cv2.IMREAD_GRAYSCALE
+MOTION_CONTINUATION_PERIOD = 1.0
 
 class Analyzer():
 
     logger = getLogger('doorcam.analyzer')
 
-    def __init__(self, cam: Camera, max_fps:int, delta_threshold:int, contour_min_area:int, undistort:bool, undistort_balance:float, callbacks:set=None):
+    def __init__(self, cam: Camera, max_fps:int, delta_threshold:int, contour_min_area:int, undistort:bool, undistort_balance:float, cooldown:float, motion_end_frames:int, warmup:float, learning_rate:float, reject_lighting_changes:bool, lighting_change_threshold:float, blur_size:int, erode_iterations:int, dilate_iterations:int, profiles:list=None, callbacks:set=None, end_callbacks:set=None, box_callbacks:set=None, start:bool=True):
         self.logger.debug(f'Intializing motion analyzer...')
         self.camera = cam
+        self.base_delta_threshold = delta_threshold
+        self.base_contour_min_area = contour_min_area
         self.delta_threshold = delta_threshold
         self.contour_min_area = contour_min_area
+        self.profiles = profiles
+        self.profile = None
+        self.cooldown = cooldown
+        self.last_motion = 0
+        self.suppressed_count = 0
+        self.motion_count = 0
+        self.motion_end_frames = motion_end_frames
+        self.motion_start = None
+        self.quiet_frames = 0
+        self.warmup = warmup
+        self.learning_rate = learning_rate
+        self.reject_lighting_changes = reject_lighting_changes
+        self.lighting_change_threshold = lighting_change_threshold
+        self.rejected_count = 0
+        self.blur_size = blur_size
+        self.erode_iterations = erode_iterations
+        self.dilate_iterations = dilate_iterations
+        self.warmup_until = time.time() + warmup if warmup else None
+        self.frame_average = None
+        self.end_callbacks = end_callbacks
+        self.box_callbacks = box_callbacks
         self.frame_count = 0
+        self.skipped_count = 0
+        self.late_count = 0
         self.fps = 0
         self.max_fps = max_fps
         self.setup_undistort(undistort, undistort_balance)
         self.callbacks = callbacks
-        self.analysis_fps_thread = Thread(target=self.analysis_fps_loop, daemon=True)
-        self.analysis_fps_thread.start()
-        self.analysis_thread = Thread(target=self.analysis_loop, daemon=True)
-        self.analysis_thread.start()
+        if start:
+            self.camera.add_status_callback(self.restart_warmup)
+            self.analysis_fps_thread = Thread(target=self.analysis_fps_loop, daemon=True)
+            self.analysis_fps_thread.start()
+            self.analysis_thread = Thread(target=self.analysis_loop, daemon=True)
+            self.analysis_thread.start()
         self.logger.debug(f'Motion analyzer initialized!')
         
     def analysis_loop(self):
-        frame_average = None
         interval = 1.0/self.max_fps
         checkpoint = time.time()
+        last_id = None
         while True:
+            self.update_profile()
+            while self.camera.frame_id == last_id:
+                time.sleep(0.001)
+            frame_id = self.camera.frame_id
+            if last_id != None:
+                self.skipped_count += frame_id - last_id - 1
+            last_id = frame_id
             try:
-                frame = cv2.imdecode(self.camera.current_jpg, ANALYZER_DECODE_FLAGS)
-                if self.undistort:
-                    frame = cv2.remap(frame, self.undistort_map1, self.undistort_map2, interpolation=cv2.INTER_LINEAR, borderMode=cv2.BORDER_CONSTANT)
-                frame = cv2.GaussianBlur(frame, (21,21), 0)
+                boxes, largest = self.detect(self.camera.current_jpg)
             except Exception as e:
                 self.logger.error(e)
                 continue
-            if frame_average is None:
-                frame_average = frame.copy().astype('float')
-            cv2.accumulateWeighted(frame, frame_average, 0.5)
-            frame_delta = cv2.absdiff(frame, cv2.convertScaleAbs(frame_average))
-            ret, frame_threshold = cv2.threshold(frame_delta, self.delta_threshold, 255, cv2.THRESH_BINARY)
-            frame_threshold = cv2.dilate(frame_threshold, None, iterations=2)
-            contours, hierarchy = cv2.findContours(frame_threshold.copy(), cv2.RETR_EXTERNAL, cv2.CHAIN_APPROX_SIMPLE)
-            activate = False
-            for contour in contours:
-                area = cv2.contourArea(contour)
-                if area > self.contour_min_area:
-                    self.logger.debug(f'Contour of {area} is above minimum area threshold of {self.contour_min_area}')
-                    activate = True
-            if activate:
-                self.logger.info(f'Motion detected, triggering callbacks')
-                if self.callbacks != None:
-                    for callback in self.callbacks:
-                        Thread(target=callback, daemon=True).start()
+            if len(boxes) > 0:
+                if self.box_callbacks != None:
+                    for callback in self.box_callbacks:
+                        Thread(target=callback, args=(boxes, ), daemon=True).start()
+                self.trigger_motion(max(x[4] for x in boxes))
+                self.quiet_frames = 0
+            elif self.motion_start != None:
+                self.quiet_frames += 1
+                if self.quiet_frames >= self.motion_end_frames:
+                    self.end_motion()
             self.frame_count += 1
             now = time.time()
+            if now - checkpoint > interval * 1.5:
+                self.late_count += 1
             while(now - checkpoint < interval):
                 time.sleep(0.001)
                 now = time.time()
             checkpoint = now
     
+    def detect(self, jpg):
+        frame = cv2.imdecode(jpg, ANALYZER_DECODE_FLAGS)
+        if self.undistort:
+            frame = cv2.remap(frame, self.undistort_map1, self.undistort_map2, interpolation=cv2.INTER_LINEAR, borderMode=cv2.BORDER_CONSTANT)
+        frame = cv2.GaussianBlur(frame, (self.blur_size, self.blur_size), 0)
+        if self.frame_average is None or self.frame_average.shape != frame.shape:
+            self.frame_average = frame.copy().astype('float')
+        cv2.accumulateWeighted(frame, self.frame_average, self.learning_rate)
+        frame_delta = cv2.absdiff(frame, cv2.convertScaleAbs(self.frame_average))
+        ret, frame_threshold = cv2.threshold(frame_delta, self.delta_threshold, 255, cv2.THRESH_BINARY)
+        if self.erode_iterations > 0:
+            frame_threshold = cv2.erode(frame_threshold, None, iterations=self.erode_iterations)
+        frame_threshold = cv2.dilate(frame_threshold, None, iterations=self.dilate_iterations)
+        boxes = []
+        largest = 0
+        if self.warming_up():
+            return boxes, largest
+        contours, hierarchy = cv2.findContours(frame_threshold.copy(), cv2.RETR_EXTERNAL, cv2.CHAIN_APPROX_SIMPLE)
+        for contour in contours:
+            area = cv2.contourArea(contour)
+            largest = max(largest, area)
+            if area > self.contour_min_area:
+                box = cv2.boundingRect(contour)
+                if self.reject_lighting_changes and self.is_lighting_change(frame_delta, frame_threshold, box):
+                    self.rejected_count += 1
+                    continue
+                self.logger.debug(f'Contour of {area} is above minimum area threshold of {self.contour_min_area}')
+                boxes.append((*box, area))
+        return boxes, largest
+
+    def is_lighting_change(self, frame_delta, frame_threshold, box):
+        x, y, w, h = box
+        mean, stddev = cv2.meanStdDev(frame_delta[y:y + h, x:x + w], mask=frame_threshold[y:y + h, x:x + w])
+        variation = stddev[0][0] / mean[0][0] if mean[0][0] > 0 else 0
+        if variation < self.lighting_change_threshold:
+            self.logger.debug(f'Contour at {box} changed uniformly (variation {variation:.2f}), rejecting it as a lighting change')
+            return True
+        return False
+
+    def warming_up(self):
+        if self.warmup_until == None:
+            return False
+        if time.time() < self.warmup_until:
+            return True
+        self.warmup_until = None
+        self.logger.info('Warm-up finished, motion detection is active')
+        return False
+
+    def restart(self):
+        self.frame_average = None
+        if self.warmup:
+            self.warmup_until = time.time() + self.warmup
+        self.logger.info('Restarted, background model reset')
+        return True
+
+    def restart_warmup(self, connected:bool):
+        if connected and self.warmup:
+            self.frame_average = None
+            self.warmup_until = time.time() + self.warmup
+            self.logger.info(f'Camera reconnected, warming up for {self.warmup} seconds')
+
+    def trigger_motion(self, area):
+        now = time.time()
+        since_last = now - self.last_motion
+        if since_last > MOTION_CONTINUATION_PERIOD and since_last < self.cooldown:
+            self.suppressed_count += 1
+            self.logger.debug(f'Motion suppressed, {since_last:.1f} seconds into {self.cooldown} second cooldown')
+            return
+        self.last_motion = now
+        self.motion_count += 1
+        if self.motion_start == None:
+            self.motion_start = now
+        self.logger.info(f'Motion detected, triggering callbacks')
+        if self.callbacks != None:
+            for callback in self.callbacks:
+                Thread(target=callback, args=(area, ), daemon=True).start()
+
+    def end_motion(self):
+        duration = time.time() - self.motion_start
+        self.motion_start = None
+        self.quiet_frames = 0
+        self.logger.info(f'Motion ended after {duration:.1f} seconds, triggering callbacks')
+        if self.end_callbacks != None:
+            for callback in self.end_callbacks:
+                Thread(target=callback, args=(duration, ), daemon=True).start()
+
+    def set_sensitivity(self, delta_threshold:int, contour_min_area:int, profiles:list=None):
+        self.base_delta_threshold = delta_threshold
+        self.base_contour_min_area = contour_min_area
+        self.delta_threshold = delta_threshold
+        self.contour_min_area = contour_min_area
+        self.profiles = profiles
+        self.profile = None
+        self.update_profile()
+
+    def update_profile(self):
+        profile = None
+        if self.profiles:
+            now = datetime.datetime.now().time()
+            for candidate in self.profiles:
+                if time_in_window(now, candidate['start'], candidate['end']):
+                    profile = candidate
+                    break
+        if profile is not self.profile:
+            self.profile = profile
+            if profile == None:
+                self.delta_threshold = self.base_delta_threshold
+                self.contour_min_area = self.base_contour_min_area
+                self.logger.info('Switched to base sensitivity profile')
+            else:
+                self.delta_threshold = profile.get('delta_threshold', self.base_delta_threshold)
+                self.contour_min_area = profile.get('contour_minimum_area', self.base_contour_min_area)
+                self.logger.info(f'Switched to sensitivity profile {profile["name"]}')
+
     def analysis_fps_loop(self):
         checkpoint = time.time()
         while True:
@@ -81,9 +223,21 @@ class Analyzer():
         else:
             self.callbacks = set((callback,))
     
+    def add_end_callback(self, callback):
+        if self.end_callbacks != None:
+            self.end_callbacks.add(callback)
+        else:
+            self.end_callbacks = set((callback,))
+
+    def add_box_callback(self, callback):
+        if self.box_callbacks != None:
+            self.box_callbacks.add(callback)
+        else:
+            self.box_callbacks = set((callback,))
+
     def remove_callback(self, callback):
         if self.callbacks != None and callback in self.callbacks:
-            if len(self.callbacks == 1):
+            if len(self.callbacks) == 1:
                 self.callbacks = None
             else:
                 self.callbacks.remove(callback)
@@ -0,0 +1,91 @@
+from threading import Thread
+import time
+import datetime
+from logging import getLogger
+
+def schedule_armed(schedule:list, now:datetime.datetime):
+    for window in schedule:
+        start, end, days = window['start'], window['end'], window['days']
+        today = now.time()
+        if start < end:
+            if now.weekday() in days and start <= today < end:
+                return True
+        elif (now.weekday() in days and today >= start) or ((now.weekday() - 1) % 7 in days and today < end):
+            return True
+    return False
+
+class ArmState():
+
+    logger = getLogger('doorcam.arm')
+
+    def __init__(self, rearm_timeout=None, schedule:list=None, timezone=None, callbacks:set=None):
+        self.armed = True
+        self.rearm_timeout = rearm_timeout
+        self.rearm_time = None
+        self.schedule = schedule
+        self.timezone = timezone
+        self.override = False
+        self.scheduled = None
+        self.callbacks = callbacks
+        if self.schedule:
+            self.scheduled = schedule_armed(self.schedule, self.now())
+            self.armed = self.scheduled
+            self.logger.info(f'{"Armed" if self.armed else "Disarmed"} by schedule')
+        self.arm_thread = Thread(target=self.arm_loop, daemon=True)
+        self.arm_thread.start()
+
+    def now(self):
+        return datetime.datetime.now(self.timezone)
+
+    def set_armed(self, armed:bool, rearm_after=None):
+        if self.schedule:
+            self.override = True
+        self.apply(armed, rearm_after)
+
+    def apply(self, armed:bool, rearm_after=None):
+        if not armed:
+            if rearm_after == None:
+                rearm_after = self.rearm_timeout
+            self.rearm_time = time.time() + rearm_after if rearm_after else None
+        else:
+            self.rearm_time = None
+        if armed != self.armed:
+            self.armed = armed
+            if armed:
+                self.logger.info('Armed, motion will be captured')
+            elif self.rearm_time != None:
+                self.logger.info(f'Disarmed, re-arming in {int(self.rearm_time - time.time())} seconds')
+            else:
+                self.logger.info('Disarmed')
+            if self.callbacks != None:
+                for callback in self.callbacks:
+                    Thread(target=callback, args=(armed, ), daemon=True).start()
+
+    def toggle(self):
+        self.set_armed(not self.armed)
+
+    def arm_loop(self):
+        while True:
+            if self.rearm_time != None and time.time() >= self.rearm_time:
+                self.apply(True)
+            if self.schedule:
+                scheduled = schedule_armed(self.schedule, self.now())
+                if scheduled != self.scheduled:
+                    self.scheduled = scheduled
+                    if self.override:
+                        self.logger.info('Reached a scheduled boundary, manual override cleared')
+                    self.override = False
+                    self.apply(scheduled, 0)
+            time.sleep(1)
+
+    def gate(self, callback):
+        def gated(*args):
+            if self.armed:
+                callback(*args)
+        return gated
+
+    def add_callback(self, callback):
+        if self.callbacks != None:
+            self.callbacks.add(callback)
+        else:
+            self.callbacks = set((callback,))
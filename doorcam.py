@@ -1,96 +1,264 @@
-import cv2
-import numpy as np
-from threading import Thread
-import time
-import logging
-
-class Camera():
-
-    logger = logging.getLogger('doorcam.camera')
-
-    def __init__(self, index:int, resolution:tuple, rotation, max_fps:int, fourcc, undistort_K:np.array, undistort_D:np.array, update_callbacks:set=None):
-        self.logger.debug(f'Initializing camera at index {index}')
-        self.index = index
-        self.resolution = resolution
-        self.rotation = rotation
-        self.fourcc = fourcc
-        self.frame_count = 0
-        self.max_fps = max_fps
-        self.fps = 0
-        self.undistort_K = undistort_K
-        self.undistort_D = undistort_D
-        self.current_jpg = None
-        self.update_callbacks = update_callbacks
-        self.open()
-        self.capture_thread = Thread(target=self.capture_loop, daemon=True)
-        self.capture_thread.start()
-        self.fps_thread = Thread(target=self.fps_loop, daemon=True)
-        self.fps_thread.start()
-        self.logger.debug(f'Camera at index {index} is intialized!')
-    
-    def add_callback(self, callback):
-        if self.update_callbacks != None:
-            self.update_callbacks.add(callback)
-        else:
-            self.update_callbacks = set((callback,))
-    
-    def remove_callback(self, callback):
-        if self.update_callbacks != None and callback in self.update_callbacks:
-            if len(self.update_callbacks == 1):
-                self.update_callbacks = None
-            else:
-                self.update_callbacks.remove(callback)
-
-    def capture_loop(self):
-        while True:
-            try:
-                ret, frame = self.cap.read()
-                if ret:
-                    self.current_jpg = frame
-                    self.frame_count += 1
-                    if self.update_callbacks != None:
-                        for callback in self.update_callbacks:
-                            Thread(target=callback, args=(frame, ), daemon=True).start()
-            except Exception as e:
-                self.logger.error(e)
-                time.sleep(1)
-    
-    def fps_loop(self):
-        checkpoint = time.time()
-        while True:
-            now = time.time()
-            if now - checkpoint >= 1:
-                self.fps = self.frame_count
-                self.frame_count = 0
-                checkpoint = now
-            else:
-                time.sleep(0.1)
-    
-    def open(self):
-        self.cap = cv2.VideoCapture(self.index, cv2.CAP_V4L2)
-        self.cap.set(cv2.CAP_PROP_FOURCC, self.fourcc)
-        self.cap.set(cv2.CAP_PROP_CONVERT_RGB, 0)
-        self.cap.set(cv2.CAP_PROP_FRAME_WIDTH, self.resolution[0])
-        self.cap.set(cv2.CAP_PROP_FRAME_HEIGHT, self.resolution[1])
-        self.cap.set(cv2.CAP_PROP_FPS, self.max_fps)
-        self.cap.set(cv2.CAP_PROP_BUFFERSIZE, 4)
-        return self.cap
-    
-    def close(self):
-        self.cap.release()
-    
-    def read(self):
-        ret, image = self.cap.read()
-        if ret:
-            return image
-        else:
-            if self.cap.isOpened():
-                raise CameraReadWhileClosed
-            else:
-                raise CameraReadError
-
-class CameraReadError(Exception):
-    pass
-
-class CameraReadWhileClosed(CameraReadError):
+import cv2
+import numpy as np
+from threading import Thread
+import time
+import logging
+import subprocess
+import re
+
+RECONNECT_BACKOFF_MIN = 1
+FRAME_GAP_FACTOR = 1.5
+JPEG_QUALITY_CONTROL = 'compression_quality'
+MJPG_FOURCC = cv2.VideoWriter_fourcc(*'MJPG')
+FORMAT_PATTERN = re.compile(r"\[\d+\]: '(\w{4})'")
+SIZE_PATTERN = re.compile(r'Size: Discrete (\d+)x(\d+)')
+INTERVAL_PATTERN = re.compile(r'\(([\d.]+) fps\)')
+
+class CameraFormatError(Exception):
+    pass
+
+# Every call decodes the whole JPEG and converts it from BGR, nothing is cached
+def jpg_to_rgb(jpg):
+    image = cv2.imdecode(jpg, cv2.IMREAD_COLOR)
+    if image is None:
+        raise ValueError('Could not decode JPEG')
+    return cv2.cvtColor(image, cv2.COLOR_BGR2RGB), image.shape[1], image.shape[0]
+
+class Camera():
+
+    logger = logging.getLogger('doorcam.camera')
+
+    def __init__(self, index:int, resolution:tuple, rotation, max_fps:int, fourcc, undistort_K:np.array, undistort_D:np.array, reconnect_timeout:int, reconnect_backoff_max:int, jpeg_quality:int=None, strict_format:bool=False, update_callbacks:set=None, status_callbacks:set=None):
+        self.logger.debug(f'Initializing camera at index {index}')
+        self.index = index
+        self.resolution = resolution
+        self.rotation = rotation
+        self.fourcc = fourcc
+        self.raw = fourcc != MJPG_FOURCC
+        self.frame_count = 0
+        self.frame_id = 0
+        self.frame_time = None
+        self.max_fps = max_fps
+        self.fps = 0
+        self.undistort_K = undistort_K
+        self.undistort_D = undistort_D
+        self.current_jpg = None
+        self.update_callbacks = update_callbacks
+        self.status_callbacks = status_callbacks
+        self.reconnect_timeout = reconnect_timeout
+        self.reconnect_backoff_max = reconnect_backoff_max
+        self.jpeg_quality = jpeg_quality
+        self.strict_format = strict_format
+        self.reconnect_count = 0
+        self.gap_count = 0
+        self.restart_requested = False
+        self.connected = True
+        self.last_frame_time = time.time()
+        self.open()
+        self.capture_thread = Thread(target=self.capture_loop, daemon=True)
+        self.capture_thread.start()
+        self.fps_thread = Thread(target=self.fps_loop, daemon=True)
+        self.fps_thread.start()
+        self.logger.debug(f'Camera at index {index} is intialized!')
+    
+    def add_callback(self, callback):
+        if self.update_callbacks != None:
+            self.update_callbacks.add(callback)
+        else:
+            self.update_callbacks = set((callback,))
+    
+    def remove_callback(self, callback):
+        if self.update_callbacks != None and callback in self.update_callbacks:
+            if len(self.update_callbacks) == 1:
+                self.update_callbacks = None
+            else:
+                self.update_callbacks.remove(callback)
+
+    def add_status_callback(self, callback):
+        if self.status_callbacks != None:
+            self.status_callbacks.add(callback)
+        else:
+            self.status_callbacks = set((callback,))
+
+    def set_connected(self, connected:bool):
+        if connected != self.connected:
+            self.connected = connected
+            if self.status_callbacks != None:
+                for callback in self.status_callbacks:
+                    Thread(target=callback, args=(connected, ), daemon=True).start()
+
+    def restart(self):
+        if self.restart_requested:
+            return False
+        self.restart_requested = True
+        return True
+
+    def reconnect(self, backoff):
+        self.logger.warning(f'No frames from camera at index {self.index} for {self.reconnect_timeout} seconds, reconnecting in {backoff} seconds')
+        self.set_connected(False)
+        self.close()
+        time.sleep(backoff)
+        self.open()
+        self.reconnect_count += 1
+        return min(backoff * 2, self.reconnect_backoff_max)
+
+    def capture_loop(self):
+        last_frame = time.time()
+        previous_frame = None
+        backoff = RECONNECT_BACKOFF_MIN
+        while True:
+            try:
+                if self.restart_requested:
+                    self.logger.info(f'Restarting camera at index {self.index}')
+                    self.close()
+                    self.open()
+                    self.restart_requested = False
+                    last_frame = time.time()
+                    previous_frame = None
+                ret, frame = self.cap.read()
+                if not ret and time.time() - last_frame > self.reconnect_timeout:
+                    backoff = self.reconnect(backoff)
+                    last_frame = time.time()
+                    previous_frame = None
+                if ret:
+                    last_frame = time.time()
+                    if previous_frame != None:
+                        self.check_gap(last_frame - previous_frame)
+                    previous_frame = last_frame
+                    self.last_frame_time = last_frame
+                    if self.raw:
+                        frame = self.encode(frame)
+                    if not self.connected:
+                        self.logger.info(f'Camera at index {self.index} reconnected')
+                        self.set_connected(True)
+                        backoff = RECONNECT_BACKOFF_MIN
+                    self.frame_time = last_frame
+                    self.current_jpg = frame
+                    self.frame_id += 1
+                    self.frame_count += 1
+                    if self.update_callbacks != None:
+                        for callback in self.update_callbacks:
+                            Thread(target=callback, args=(frame, ), daemon=True).start()
+            except Exception as e:
+                self.logger.error(e)
+                time.sleep(1)
+    
+    def check_gap(self, interval):
+        if interval * self.max_fps >= FRAME_GAP_FACTOR:
+            missing = round(interval * self.max_fps) - 1
+            self.gap_count += missing
+            self.logger.debug(f'Camera at index {self.index} delivered no frames for {interval:.3f}s, about {missing} frames missing')
+
+    def fps_loop(self):
+        checkpoint = time.time()
+        while True:
+            now = time.time()
+            if now - checkpoint >= 1:
+                self.fps = self.frame_count
+                self.frame_count = 0
+                checkpoint = now
+            else:
+                time.sleep(0.1)
+    
+    def open(self):
+        self.cap = cv2.VideoCapture(self.index, cv2.CAP_V4L2)
+        self.cap.set(cv2.CAP_PROP_FOURCC, self.fourcc)
+        self.cap.set(cv2.CAP_PROP_CONVERT_RGB, 1 if self.raw else 0)
+        self.cap.set(cv2.CAP_PROP_FRAME_WIDTH, self.resolution[0])
+        self.cap.set(cv2.CAP_PROP_FRAME_HEIGHT, self.resolution[1])
+        self.cap.set(cv2.CAP_PROP_FPS, self.max_fps)
+        self.cap.set(cv2.CAP_PROP_BUFFERSIZE, 4)
+        self.negotiate_format()
+        if self.jpeg_quality != None and not self.raw:
+            self.set_jpeg_quality(self.jpeg_quality)
+        return self.cap
+
+    def actual_format(self):
+        resolution = (int(self.cap.get(cv2.CAP_PROP_FRAME_WIDTH)), int(self.cap.get(cv2.CAP_PROP_FRAME_HEIGHT)))
+        return resolution, int(round(self.cap.get(cv2.CAP_PROP_FPS)))
+
+    def supported_formats(self):
+        device = f'/dev/video{self.index}'
+        try:
+            output = subprocess.run(['v4l2-ctl', '-d', device, '--list-formats-ext'], capture_output=True, text=True, timeout=5).stdout
+        except (OSError, subprocess.SubprocessError) as e:
+            self.logger.warning(f'Could not list the formats of {device}: {e}')
+            return {}
+        formats = {}
+        fourcc = size = None
+        for line in output.splitlines():
+            if match := FORMAT_PATTERN.search(line):
+                fourcc = cv2.VideoWriter_fourcc(*match.group(1))
+            elif match := SIZE_PATTERN.search(line):
+                size = (int(match.group(1)), int(match.group(2)))
+                if fourcc == self.fourcc:
+                    formats[size] = []
+            elif (match := INTERVAL_PATTERN.search(line)) and fourcc == self.fourcc and size != None:
+                formats[size].append(float(match.group(1)))
+        return formats
+
+    def negotiate_format(self):
+        resolution, fps = self.actual_format()
+        if resolution == tuple(self.resolution) and (fps == 0 or fps == self.max_fps):
+            return
+        requested = f'{self.resolution[0]}x{self.resolution[1]}@{self.max_fps}'
+        if self.strict_format:
+            raise CameraFormatError(f'Camera at index {self.index} does not support {requested}, it gave {resolution[0]}x{resolution[1]}@{fps}')
+        formats = self.supported_formats()
+        if formats:
+            target = self.resolution[0] * self.resolution[1]
+            size = min(formats, key=lambda x: (abs(x[0] * x[1] - target), -x[0] * x[1]))
+            rates = formats[size]
+            self.cap.set(cv2.CAP_PROP_FRAME_WIDTH, size[0])
+            self.cap.set(cv2.CAP_PROP_FRAME_HEIGHT, size[1])
+            if rates:
+                self.cap.set(cv2.CAP_PROP_FPS, min(rates, key=lambda x: abs(x - self.max_fps)))
+            resolution, fps = self.actual_format()
+        self.logger.warning(f'Camera at index {self.index} does not support {requested}, fell back to {resolution[0]}x{resolution[1]}@{fps}')
+        self.resolution = resolution
+        if fps > 0:
+            self.max_fps = fps
+
+    def current_rgb(self):
+        # A full decode of the newest frame per call, see jpg_to_rgb
+        if self.current_jpg is None:
+            return None
+        return jpg_to_rgb(self.current_jpg)
+
+    def encode(self, frame):
+        params = (cv2.IMWRITE_JPEG_QUALITY, self.jpeg_quality) if self.jpeg_quality != None else ()
+        ret, image = cv2.imencode('.jpg', frame, params)
+        return image
+
+    def set_jpeg_quality(self, quality:int):
+        device = f'/dev/video{self.index}'
+        try:
+            controls = subprocess.run(['v4l2-ctl', '-d', device, '--list-ctrls'], capture_output=True, text=True, timeout=5).stdout
+            if JPEG_QUALITY_CONTROL not in controls:
+                self.logger.warning(f'{device} does not expose a JPEG quality control, ignoring jpeg_quality')
+                return
+            subprocess.run(['v4l2-ctl', '-d', device, f'--set-ctrl={JPEG_QUALITY_CONTROL}={quality}'], check=True, capture_output=True, timeout=5)
+            self.logger.info(f'Set JPEG quality of {device} to {quality}')
+        except FileNotFoundError:
+            self.logger.warning('v4l2-ctl is not installed, ignoring jpeg_quality')
+        except (subprocess.SubprocessError, OSError) as e:
+            self.logger.warning(f'Could not set JPEG quality of {device}: {e}')
+    
+    def close(self):
+        self.cap.release()
+    
+    def read(self):
+        ret, image = self.cap.read()
+        if ret:
+            return image
+        else:
+            if self.cap.isOpened():
+                raise CameraReadWhileClosed
+            else:
+                raise CameraReadError
+
+class CameraReadError(Exception):
+    pass
+
+class CameraReadWhileClosed(CameraReadError):
     pass
\ No newline at end of file
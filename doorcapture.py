@@ -1,39 +1,117 @@
 from doorcam import Camera
-from threading import Thread
+from threading import Thread, Lock
+import multiprocessing
 import time
 import datetime
 import os
+import re
+import errno
 import shutil
+import zipfile
 import cv2
-from logging import getLogger
+import numpy as np
+import yaml
+from logging import getLogger, basicConfig
 
 TIME_FORMAT = "%Y-%m-%d_%H-%M-%S-%f"
-TIMESTAMP_FORMAT = "%H:%M:%S %m/%d/%Y"
+OVERLAY_MARGIN = 20
+OVERLAY_SCALE = 1
 TRIM_DELAY = 86400
 TRIM_CHECK_INTERVAL = 300
+METADATA_FILE = 'metadata.yaml'
+MIN_FREE_SPACE = 50 * 1024 * 1024
+SHUTDOWN_TIMEOUT = 5
+POST_PROCESS_RETRY_DELAY = 60
+POST_PROCESS_KILL_TIMEOUT = 5
+POST_PROCESS_CONTEXT = multiprocessing.get_context('forkserver')
+POST_PROCESS_CONTEXT.set_forkserver_preload(['doorcapture'])
+PREROLL_SPOOL_DIR = '.preroll'
+DELETED_DIR = '.deleted'
+MOTION_BOXES_FILE = 'motion_boxes.yaml'
+MOTION_BOX_PERIOD = 0.5
+DEDUP_MOTION_PERIOD = 1.0
+MOTION_BOX_COLOR = (0, 0, 255)
+VIDEO_CODECS = {
+    'mp4': 'mp4v',
+    'mkv': 'mp4v',
+    'webm': 'VP80'
+}
+VIDEO_ENCODER = f'opencv {cv2.__version__}'
+ROTATION_NAMES = {
+    cv2.ROTATE_90_CLOCKWISE: 'ROTATE_90_CLOCKWISE',
+    cv2.ROTATE_180: 'ROTATE_180',
+    cv2.ROTATE_90_COUNTERCLOCKWISE: 'ROTATE_90_COUNTERCLOCKWISE'
+}
 
 class Capture():
 
     logger = getLogger('doorcam.capture')
 
-    def __init__(self, camera: Camera, preroll_time, postroll_time, capture_path, timestamp, rotation, video_encode, keep_images, trim_old, trim_limit):
+    def __init__(self, camera: Camera, preroll_time, postroll_time, postroll_max, postroll_scale_area, postroll_extension_fraction, capture_path, timestamp, rotation, video_encode, keep_images, trim_old, trim_limit, min_events_kept, max_events_kept, fsync_interval, fallback_path, max_duration, dedup, dedup_threshold, preroll_eviction, preroll_memory, preroll_memory_budget, container, roll_unit, draw_motion_boxes, timestamp_position, timestamp_format, timestamp_utc, timestamp_color, label, label_position, logo_path, logo_position, name_template, crop_region, post_process_timeout, post_process_retries, post_process_workers, error_callbacks:set=None, start_callbacks:set=None, finish_callbacks:set=None):
         self.camera = camera
         self.preroll = preroll_time
         self.postroll = postroll_time
+        self.roll_unit = roll_unit
+        self.draw_motion_boxes = draw_motion_boxes
+        self.motion_boxes = (0, [])
+        self.postroll_max = postroll_max
+        self.postroll_scale_area = postroll_scale_area
+        self.postroll_extension_fraction = postroll_extension_fraction
+        self.activate_area = None
+        self.active_event = None
+        self.missed_count = 0
+        self.max_duration = max_duration
+        self.dedup = dedup
+        self.dedup_threshold = dedup_threshold
         self.path = os.path.abspath(capture_path)
         self.rotation = rotation
         self.timestamp = timestamp
+        self.timestamp_position = timestamp_position
+        self.timestamp_format = timestamp_format
+        self.timestamp_utc = timestamp_utc
+        self.timestamp_color = tuple(reversed(timestamp_color))
+        self.label = label
+        self.label_position = label_position
+        self.logo_path = logo_path
+        self.logo_position = logo_position
+        self.name_template = name_template
+        self.crop_region = crop_region
+        self.post_process_timeout = post_process_timeout
+        self.post_process_retries = post_process_retries
+        self.post_process_lock = Lock()
+        self.post_processing = set()
+        self.failed_count = 0
+        if logo_path != None and cv2.imread(logo_path, cv2.IMREAD_UNCHANGED) is None:
+            self.logger.error(f'Could not read logo from {logo_path}')
         self.video_encode = video_encode
+        self.container = container
         self.keep_images = keep_images
         if not os.path.isdir(self.path):
             os.mkdir(self.path)
+        self.fallback_path = None
+        if fallback_path != None:
+            self.fallback_path = os.path.abspath(fallback_path)
+            try:
+                if not os.path.isdir(self.fallback_path):
+                    os.mkdir(self.fallback_path)
+            except OSError as e:
+                self.logger.error(f'Could not create fallback capture path {self.fallback_path}: {e}')
+        self.error_callbacks = error_callbacks
+        self.start_callbacks = start_callbacks
+        self.finish_callbacks = finish_callbacks
         self.activate = False
         self.trim_old = trim_old
         self.trim_limit = trim_limit
-        self.queue = CaptureQueue(self.camera, self.preroll)
-        self.post_process_queue = []
-        self.post_process_thread = Thread(target=self.post_process_loop, daemon=True)
-        self.post_process_thread.start()
+        self.min_events_kept = min_events_kept
+        self.max_events_kept = max_events_kept
+        self.fsync_interval = fsync_interval
+        self.unsynced_frames = []
+        self.queue = CaptureQueue(self.camera, self.preroll, 'frames' if self.roll_unit == 'frames' else preroll_eviction, preroll_memory, os.path.join(self.path, PREROLL_SPOOL_DIR) if preroll_memory != None else None, preroll_memory_budget * 1024 * 1024 if preroll_memory_budget else None)
+        self.finalize = False
+        self.post_process_queue = self.find_unprocessed()
+        self.post_process_threads = [Thread(target=self.post_process_loop, daemon=True) for i in range(post_process_workers)]
+        for thread in self.post_process_threads:
+            thread.start()
         self.capture_thread = Thread(target=self.capture_loop, daemon=True)
         self.capture_thread.start()
         if self.trim_old:
@@ -43,52 +121,304 @@ class Capture():
 
     def capture_loop(self):
         self.frame_update = False
-        now = time.time()
-        while True:
+        while not self.finalize:
             while not self.activate:
                 time.sleep(0.001)
             self.activate = False
-            now = time.time()
-            start = now
-            dirname = datetime.datetime.fromtimestamp(now).strftime(TIME_FORMAT)
-            dirname = os.path.join(self.path, dirname)
-            self.logger.info(f'Capturing event and storing images at {dirname}')
-            if not os.path.isdir(dirname):
-                os.mkdir(dirname)
-            imgdir = os.path.join(dirname, 'images')
-            if not os.path.isdir(imgdir):
-                os.mkdir(imgdir)
-            preroll = self.queue.queue.copy()
-            while now - start < self.postroll:
-                while not self.frame_update:
-                    time.sleep(0.001)
-                self.frame_update = False
-                now = time.time()
-                filename = datetime.datetime.fromtimestamp(now).strftime(TIME_FORMAT)
-                filename = os.path.join(imgdir, filename)
-                filename = filename + '.jpg'
-                with open(filename, 'wb') as out:
-                    out.write(self.camera.current_jpg)
-                if self.activate:
-                    self.activate = False
-                    start = now
-            for timestamp, image in preroll:
-                filename = datetime.datetime.fromtimestamp(timestamp).strftime(TIME_FORMAT)
-                filename = os.path.join(imgdir, filename)
-                filename = filename + '.jpg'
-                with open(filename, 'wb') as out:
-                    out.write(image)
-            self.post_process_queue.append(dirname)
+            for path in self.capture_paths():
+                dirname = self.event_name(path, datetime.datetime.now())
+                try:
+                    self.capture_event(dirname)
+                    self.post_process_queue.append(dirname)
+                    break
+                except OSError as e:
+                    self.active_event = None
+                    self.logger.error(f'Could not write event to {dirname}: {e}')
+                    self.trigger_error(f'Capture write failed: {e.strerror}')
+                    self.unsynced_frames = []
+                    shutil.rmtree(dirname, ignore_errors=True)
+
+    def event_name(self, path, timestamp:datetime.datetime):
+        name = timestamp.strftime(self.name_template).replace('{label}', (self.label or '').replace(' ', '_'))
+        dirname = os.path.join(path, name)
+        count = 1
+        while os.path.exists(dirname):
+            dirname = os.path.join(path, f'{name}_{count}')
+            count += 1
+        return dirname
+
+    def stop(self, timeout=SHUTDOWN_TIMEOUT):
+        self.finalize = True
+        deadline = time.time() + timeout
+        while self.active_event != None and time.time() < deadline:
+            time.sleep(0.01)
+        if self.active_event != None:
+            self.logger.warning(f'Timed out finalizing {self.active_event["path"]}')
+        elif len(self.post_process_queue) > 0:
+            self.logger.info(f'{len(self.post_process_queue)} events left to post-process on next start')
+
+    def find_unprocessed(self):
+        unprocessed = []
+        for path in self.capture_paths():
+            if not os.path.isdir(path):
+                continue
+            for event in sorted(os.listdir(path)):
+                event = os.path.join(path, event)
+                metadata = self.load_metadata(event)
+                if os.path.isdir(os.path.join(event, 'images')) and 'start' in metadata and 'frames' not in metadata:
+                    if metadata.get('post_process_attempts', 0) > self.post_process_retries:
+                        self.logger.debug(f'Post-processing {event} was given up on, not queueing it')
+                        continue
+                    self.logger.info(f'Found unprocessed event at {event}, queueing for post-processing')
+                    unprocessed.append(event)
+        return unprocessed
+
+    def capture_paths(self):
+        if self.fallback_path == None:
+            return [self.path]
+        else:
+            return [self.path, self.fallback_path]
+
+    def capture_event(self, dirname):
+        if shutil.disk_usage(os.path.dirname(dirname)).free < MIN_FREE_SPACE:
+            raise OSError(errno.ENOSPC, os.strerror(errno.ENOSPC), os.path.dirname(dirname))
+        now = time.monotonic()
+        clock_offset = time.time() - now
+        event_start = now
+        truncated = None
+        self.logger.info(f'Capturing event and storing images at {dirname}')
+        if not os.path.isdir(dirname):
+            os.mkdir(dirname)
+        imgdir = os.path.join(dirname, 'images')
+        if not os.path.isdir(imgdir):
+            os.mkdir(imgdir)
+        self.update_metadata(dirname, {'start': datetime.datetime.fromtimestamp(clock_offset + now)})
+        if self.start_callbacks != None:
+            for callback in self.start_callbacks:
+                Thread(target=callback, args=(os.path.basename(dirname), ), daemon=True).start()
+        preroll = self.queue.frames()
+        trigger_area = self.activate_area
+        postroll = self.scaled_postroll(trigger_area)
+        extensions = 0
+        extended = 0
+        self.active_event = {'path': dirname, 'start': now, 'last_motion': now, 'postroll': postroll, 'frames': 0, 'frames_since_motion': 0}
+        last_id = self.camera.frame_id
+        camera_gaps = self.camera.gap_count
+        missed = 0
+        last_size = None
+        last_trigger = now
+        dropped = 0
+        boxes = {}
+        while self.postroll_remaining(self.active_event, now) > 0 and not self.finalize:
+            while not self.frame_update:
+                time.sleep(0.001)
+            self.frame_update = False
+            now = time.monotonic()
+            image = self.camera.current_jpg
+            frame_id = self.camera.frame_id
+            if frame_id - last_id > 1:
+                missed += frame_id - last_id - 1
+            last_id = frame_id
+            self.active_event['frames'] += 1
+            self.active_event['frames_since_motion'] += 1
+            if self.activate:
+                self.activate = False
+                last_trigger = now
+                if self.extends_postroll(trigger_area, self.activate_area):
+                    remaining = self.postroll_remaining(self.active_event, now)
+                    postroll = self.scaled_postroll(self.activate_area)
+                    self.active_event.update({'last_motion': now, 'postroll': postroll, 'frames_since_motion': 0})
+                    extensions += 1
+                    extended += max(0, postroll - remaining)
+            if self.dedup and now - last_trigger > DEDUP_MOTION_PERIOD and last_size != None and abs(len(image) - last_size) <= last_size * self.dedup_threshold:
+                dropped += 1
+            else:
+                last_size = len(image)
+                filename = self.write_frame(imgdir, clock_offset + now, image)
+                if self.draw_motion_boxes and now - self.motion_boxes[0] < MOTION_BOX_PERIOD:
+                    boxes[filename] = self.motion_boxes[1]
+            if self.max_duration and now - event_start >= self.max_duration:
+                self.logger.info(f'Capture at {dirname} reached the maximum duration of {self.max_duration} seconds, finalizing')
+                truncated = 'max_duration'
+                break
+        if self.finalize:
+            truncated = 'shutdown'
+        for timestamp, image in preroll:
+            self.write_frame(imgdir, clock_offset + timestamp, image)
+        self.flush(imgdir)
+        if len(boxes) > 0:
+            with open(os.path.join(dirname, MOTION_BOXES_FILE), 'w') as stream:
+                stream.write(yaml.safe_dump(boxes))
+        frames = self.active_event['frames']
+        self.active_event = None
+        camera_gaps = self.camera.gap_count - camera_gaps
+        self.missed_count += missed
+        if missed > 0 or camera_gaps > 0:
+            self.logger.warning(f'Capture at {dirname} is missing frames: {missed} delivered by the camera were not captured in time, and the camera skipped about {camera_gaps}')
+        self.update_metadata(dirname, {
+            'postroll': postroll,
+            'extensions': extensions,
+            'extended': extended,
+            'truncated': truncated,
+            'dedup_ratio': dropped / frames if self.dedup and frames > 0 else None,
+            'missed_frames': missed,
+            'camera_gaps': camera_gaps
+        })
+
+    def get_active_capture_details(self):
+        event = self.active_event
+        if event == None:
+            return []
+        now = time.monotonic()
+        return [{
+            'event': os.path.basename(event['path']),
+            'duration': now - event['start'],
+            'frames': event['frames'],
+            'postroll_remaining': self.postroll_remaining(event, now)
+        }]
+
+    def postroll_remaining(self, event, now):
+        if self.roll_unit == 'frames':
+            return max(0, event['postroll'] - event['frames_since_motion'])
+        return max(0, event['postroll'] - (now - event['last_motion']))
+
+    def extends_postroll(self, trigger_area, area):
+        if not self.postroll_extension_fraction or trigger_area == None or area == None:
+            return True
+        return area >= trigger_area * self.postroll_extension_fraction
+
+    def scaled_postroll(self, area):
+        if self.postroll_max == None or area == None:
+            return self.postroll
+        scale = min(1.0, area / self.postroll_scale_area)
+        return self.postroll + (self.postroll_max - self.postroll) * scale
+
+    def trigger_error(self, message):
+        if self.error_callbacks != None:
+            for callback in self.error_callbacks:
+                Thread(target=callback, args=(message, ), daemon=True).start()
+
+    def write_frame(self, imgdir, timestamp, image):
+        filename = datetime.datetime.fromtimestamp(timestamp).strftime(TIME_FORMAT)
+        filename = os.path.join(imgdir, filename)
+        filename = filename + '.jpg'
+        with open(filename, 'wb') as out:
+            out.write(image)
+        if self.fsync_interval:
+            self.unsynced_frames.append(filename)
+            if len(self.unsynced_frames) >= self.fsync_interval:
+                self.flush(imgdir)
+        return os.path.basename(filename)
+
+    def flush(self, imgdir):
+        if not self.fsync_interval:
+            return
+        for filename in self.unsynced_frames:
+            fd = os.open(filename, os.O_RDONLY)
+            try:
+                os.fsync(fd)
+            finally:
+                os.close(fd)
+        self.unsynced_frames = []
+        fd = os.open(imgdir, os.O_RDONLY)
+        try:
+            os.fsync(fd)
+        finally:
+            os.close(fd)
 
     def post_process_loop(self):
         while True:
-            while len(self.post_process_queue) == 0:
+            with self.post_process_lock:
+                path = None
+                while len(self.post_process_queue) > 0 and path == None:
+                    path = self.post_process_queue.pop(0)
+                    if path in self.post_processing:
+                        self.logger.debug(f'{path} is already being post-processed, dropping the duplicate')
+                        path = None
+                if path != None:
+                    self.post_processing.add(path)
+            if path == None:
                 time.sleep(1)
-            try:
-                self.post_process(self.post_process_queue.pop(0))
-            except Exception as e:
-                self.logger.error(e)
+                continue
+            result = self.run_post_process(path)
+            if result == None:
+                self.failed_count += 1
+                self.logger.error(f'Could not stop post-processing of {path}, not retrying it')
+                self.trigger_error(f'Post-processing {os.path.basename(path)} is stuck')
+                continue
+            with self.post_process_lock:
+                self.post_processing.discard(path)
+            if not result:
+                self.failed_count += 1
+                self.trigger_error(f'Post-processing {os.path.basename(path)} failed')
+                self.retry_post_process(path)
+                continue
+            if self.finish_callbacks != None:
+                for callback in self.finish_callbacks:
+                    Thread(target=callback, args=(path, ), daemon=True).start()
     
+    def run_post_process(self, path):
+        receiver, sender = POST_PROCESS_CONTEXT.Pipe(duplex=False)
+        process = POST_PROCESS_CONTEXT.Process(target=post_process_event, args=(path, self.post_process_settings(), self.logger.getEffectiveLevel(), sender), daemon=True)
+        process.start()
+        sender.close()
+        process.join(self.post_process_timeout or None)
+        if process.is_alive():
+            self.logger.error(f'Post-processing {path} did not finish within {self.post_process_timeout} seconds, stopping it and keeping its images')
+            process.terminate()
+            process.join(POST_PROCESS_KILL_TIMEOUT)
+            if process.is_alive():
+                process.kill()
+                process.join(POST_PROCESS_KILL_TIMEOUT)
+            if process.is_alive():
+                return None
+            return False
+        error = receiver.recv() if receiver.poll() else f'exited with code {process.exitcode}'
+        receiver.close()
+        if error != None:
+            self.logger.error(f'Post-processing {path} failed, keeping its images: {error}')
+            return False
+        return True
+
+    def post_process_settings(self):
+        return {
+            'max_fps': self.camera.max_fps,
+            'rotation': self.rotation,
+            'crop_region': self.crop_region,
+            'video_encode': self.video_encode,
+            'keep_images': self.keep_images,
+            'container': self.container,
+            'timestamp': self.timestamp,
+            'timestamp_position': self.timestamp_position,
+            'timestamp_format': self.timestamp_format,
+            'timestamp_utc': self.timestamp_utc,
+            'timestamp_color': self.timestamp_color,
+            'label': self.label,
+            'label_position': self.label_position,
+            'logo_path': self.logo_path,
+            'logo_position': self.logo_position
+        }
+
+    def retry_post_process(self, path):
+        if not os.path.isdir(path):
+            return
+        attempts = self.load_metadata(path).get('post_process_attempts', 0) + 1
+        self.update_metadata(path, {'post_process_attempts': attempts})
+        if attempts > self.post_process_retries:
+            self.logger.error(f'Giving up on post-processing {path} after {attempts} attempts, its images are kept')
+            return
+        delay = POST_PROCESS_RETRY_DELAY * 2 ** (attempts - 1)
+        self.logger.warning(f'Retrying post-processing of {path} in {delay} seconds, attempt {attempts + 1} of {self.post_process_retries + 1}')
+        Thread(target=self.requeue_post_process, args=(path, delay), daemon=True).start()
+
+    def requeue_post_process(self, path, delay):
+        time.sleep(delay)
+        with self.post_process_lock:
+            if path in self.post_processing or path in self.post_process_queue:
+                return
+            if os.path.isdir(path) and 'frames' not in self.load_metadata(path):
+                self.post_process_queue.append(path)
+
     def trim_loop(self):
         timestamp = time.time()
         while True:
@@ -98,31 +428,72 @@ class Capture():
                 time.sleep(TRIM_CHECK_INTERVAL)
 
     def trim_dir(self):
-        events = os.listdir(self.path)
-        valid_events = []
-        for event in events:
-            event = os.path.join(self.path, event)
-            if os.path.isdir(event):
-                try:
-                    timestamp = datetime.datetime.strptime(os.path.basename(event), TIME_FORMAT)
-                    valid_events.append((event, timestamp))
-                except Exception as e:
-                    self.logger.debug(f'{event} could not be parsed as a timestamp, ignoring for trim')
-        if len(valid_events) > 0:
-            count = 0
-            now = datetime.datetime.now()
-            cutoff = now - datetime.timedelta(days=30)
-            self.logger.debug(f'Checking for events before {cutoff.strftime(TIME_FORMAT)}')
-            for event in valid_events:
-                if event[1] < cutoff:
-                    self.logger.debug(f'Trimming {event[0]} as it is older than the specified date of {cutoff.strftime(TIME_FORMAT)}')
-                    try:
-                        shutil.rmtree(event[0])
-                        count += 1
-                    except Exception as e:
-                        self.logger.error(e)
+        trimmed = trim_events(self.capture_paths(), self.trim_limit, self.min_events_kept, self.max_events_kept)
+        self.logger.info(f'Trimmed {len(trimmed)} events')
+
+    def set_motion_boxes(self, boxes:list):
+        self.motion_boxes = (time.monotonic(), [list(x) for x in boxes])
+
+    @staticmethod
+    def load_metadata(path):
+        try:
+            with open(os.path.join(path, METADATA_FILE), 'r') as stream:
+                return yaml.safe_load(stream) or {}
+        except FileNotFoundError:
+            return {}
+
+    @staticmethod
+    def update_metadata(path, values:dict):
+        metadata = Capture.load_metadata(path)
+        metadata.update(values)
+        with open(os.path.join(path, METADATA_FILE), 'w') as stream:
+            stream.write(yaml.safe_dump(metadata))
+
+    def add_error_callback(self, callback):
+        if self.error_callbacks != None:
+            self.error_callbacks.add(callback)
+        else:
+            self.error_callbacks = set((callback,))
+
+    def add_start_callback(self, callback):
+        if self.start_callbacks != None:
+            self.start_callbacks.add(callback)
+        else:
+            self.start_callbacks = set((callback,))
+
+    def add_finish_callback(self, callback):
+        if self.finish_callbacks != None:
+            self.finish_callbacks.add(callback)
         else:
-            self.logger.debug('Did not detect any valid event directories while trimming')
+            self.finish_callbacks = set((callback,))
+
+    def trigger_capture(self, area=None):
+        self.activate_area = area
+        self.activate = True
+    
+    def trigger_frame_update(self, img):
+        self.frame_update = True
+
+class PostProcessor():
+
+    logger = getLogger('doorcam.capture')
+
+    def __init__(self, max_fps, rotation, crop_region, video_encode, keep_images, container, timestamp, timestamp_position, timestamp_format, timestamp_utc, timestamp_color, label, label_position, logo_path, logo_position):
+        self.max_fps = max_fps
+        self.rotation = rotation
+        self.crop_region = crop_region
+        self.video_encode = video_encode
+        self.keep_images = keep_images
+        self.container = container
+        self.timestamp = timestamp
+        self.timestamp_position = timestamp_position
+        self.timestamp_format = timestamp_format
+        self.timestamp_utc = timestamp_utc
+        self.timestamp_color = timestamp_color
+        self.label = label
+        self.label_position = label_position
+        self.logo = cv2.imread(logo_path, cv2.IMREAD_UNCHANGED) if logo_path != None else None
+        self.logo_position = logo_position
 
     def post_process(self, path):
         self.logger.debug(f'Post-processing images located at: {path}')
@@ -131,71 +502,374 @@ class Capture():
         for filename in os.listdir(imgpath):
             if filename[-4:].lower() == '.jpg':
                 images.append(filename)
-        if len(images) > 0 and (self.timestamp or self.video_encode):
+        resolution = None
+        if len(images) > 0 and (self.has_overlay() or self.video_encode or self.rotation != None or self.crop_region != None):
             images.sort()
+            deduped = Capture.load_metadata(path).get('dedup_ratio')
+            boxes = self.load_motion_boxes(path)
             if self.video_encode:
-                video_file = os.path.basename(path) + '.mp4'
+                video_file = f'{os.path.basename(path)}.{self.container}'
                 video_file = os.path.join(path, video_file)
-                video_resolution = self.camera.resolution
-                if self.rotation == cv2.ROTATE_90_CLOCKWISE or self.rotation == cv2.ROTATE_90_COUNTERCLOCKWISE:
-                    video_resolution = (video_resolution[1],video_resolution[0])
-                video_writer = cv2.VideoWriter(video_file, cv2.VideoWriter_fourcc(*'mp4v'), self.camera.max_fps, video_resolution)
-            for filename in images:
+            for i, filename in enumerate(images):
                 fullpath = os.path.join(imgpath, filename)
                 try:
                     image = cv2.imread(fullpath, flags=cv2.IMREAD_COLOR)
+                    for x, y, width, height, area in boxes.get(filename, []):
+                        cv2.rectangle(image, (x, y), (x + width, y + height), MOTION_BOX_COLOR, 2)
+                        cv2.putText(image, f'{area:.0f}', (x, max(y - 8, 16)), cv2.FONT_HERSHEY_COMPLEX, 0.6, MOTION_BOX_COLOR)
+                    if self.crop_region != None:
+                        image = self.crop(image)
                     if self.rotation != None:
                         image = cv2.rotate(image, self.rotation)
+                    if resolution == None:
+                        resolution = (image.shape[1], image.shape[0])
+                        if self.video_encode:
+                            video_writer = cv2.VideoWriter(video_file, cv2.VideoWriter_fourcc(*VIDEO_CODECS[self.container]), self.max_fps, resolution)
                     if self.timestamp:
-                        timestamp = datetime.datetime.strptime(filename[:-4], TIME_FORMAT)
-                        image = cv2.putText(image, timestamp.strftime(TIMESTAMP_FORMAT), (50,50), cv2.FONT_HERSHEY_COMPLEX, 1, (255,255,255))
-                        if self.keep_images:
-                            cv2.imwrite(fullpath, image)
+                        image = self.draw_timestamp(image, datetime.datetime.strptime(filename[:-4], TIME_FORMAT))
+                    if self.label != None:
+                        image = self.draw_text(image, self.label, self.label_position, self.timestamp_color)
+                    if self.logo is not None:
+                        image = self.draw_logo(image)
+                    if self.keep_images and (self.has_overlay() or self.rotation != None or self.crop_region != None or filename in boxes):
+                        cv2.imwrite(fullpath, image)
                     if self.video_encode:
-                        video_writer.write(image)
-                    if not self.keep_images:
-                        try:
-                            os.remove(fullpath)
-                        except Exception as e:
-                            self.logger.error(e)
+                        repeat = 1
+                        if deduped and i + 1 < len(images):
+                            gap = datetime.datetime.strptime(images[i + 1][:-4], TIME_FORMAT) - datetime.datetime.strptime(filename[:-4], TIME_FORMAT)
+                            repeat = max(1, round(gap.total_seconds() * self.max_fps))
+                        for _ in range(repeat):
+                            video_writer.write(image)
                 except Exception as e:
                     self.logger.error(e)
-            if self.video_encode:
+            if self.video_encode and resolution != None:
                 video_writer.release()
+                if not os.path.isfile(video_file) or os.path.getsize(video_file) == 0:
+                    raise Exception(f'Encoding {video_file} produced no video')
                 self.logger.info(f'Video of {path} encoded and saved to {video_file}')
             if not self.keep_images:
                 try:
-                    os.rmdir(imgpath)
+                    shutil.rmtree(imgpath)
                 except Exception as e:
                     self.logger.error(e)
+        Capture.update_metadata(path, {
+            'rotation': ROTATION_NAMES.get(self.rotation),
+            'crop_region': self.crop_region,
+            'frames': len(images),
+            'video': f'{os.path.basename(path)}.{self.container}' if self.video_encode and resolution != None else None,
+            'width': resolution[0] if resolution != None else None,
+            'height': resolution[1] if resolution != None else None,
+            'video_size': os.path.getsize(video_file) if self.video_encode and resolution != None else None,
+            'codec': VIDEO_CODECS[self.container] if self.video_encode and resolution != None else None,
+            'encoder': VIDEO_ENCODER if self.video_encode and resolution != None else None
+        })
 
-    def trigger_capture(self):
-        self.activate = True
-    
-    def trigger_frame_update(self, img):
-        self.frame_update = True
+    def crop(self, image):
+        x, y, width, height = self.crop_region
+        left, top = int(x * image.shape[1]), int(y * image.shape[0])
+        right, bottom = int((x + width) * image.shape[1]), int((y + height) * image.shape[0])
+        return image[top:bottom, left:right].copy()
+
+    def draw_timestamp(self, image, timestamp:datetime.datetime):
+        if self.timestamp_utc:
+            timestamp = timestamp.astimezone(datetime.timezone.utc)
+        return self.draw_text(image, timestamp.strftime(self.timestamp_format), self.timestamp_position, self.timestamp_color)
+
+    def draw_text(self, image, text:str, position:str, color:tuple):
+        (text_width, text_height), baseline = cv2.getTextSize(text, cv2.FONT_HERSHEY_COMPLEX, OVERLAY_SCALE, 1)
+        x = OVERLAY_MARGIN if position.endswith('left') else image.shape[1] - text_width - OVERLAY_MARGIN
+        y = OVERLAY_MARGIN + text_height if position.startswith('top') else image.shape[0] - baseline - OVERLAY_MARGIN
+        return cv2.putText(image, text, (x, y), cv2.FONT_HERSHEY_COMPLEX, OVERLAY_SCALE, color)
+
+    def draw_logo(self, image):
+        height, width = self.logo.shape[:2]
+        if width + OVERLAY_MARGIN > image.shape[1] or height + OVERLAY_MARGIN > image.shape[0]:
+            return image
+        x = OVERLAY_MARGIN if self.logo_position.endswith('left') else image.shape[1] - width - OVERLAY_MARGIN
+        y = OVERLAY_MARGIN if self.logo_position.startswith('top') else image.shape[0] - height - OVERLAY_MARGIN
+        region = image[y:y + height, x:x + width]
+        if self.logo.shape[2] == 4:
+            alpha = self.logo[:, :, 3:] / 255.0
+            region[:] = (alpha * self.logo[:, :, :3] + (1 - alpha) * region).astype(np.uint8)
+        else:
+            region[:] = self.logo[:, :, :3]
+        return image
+
+    def has_overlay(self):
+        return self.timestamp or self.label != None or self.logo is not None
+
+    def load_motion_boxes(self, path):
+        try:
+            with open(os.path.join(path, MOTION_BOXES_FILE), 'r') as stream:
+                return yaml.safe_load(stream) or {}
+        except FileNotFoundError:
+            return {}
+
+def post_process_event(path, settings:dict, log_level, conn):
+    basicConfig(level=log_level, format='%(asctime)s [%(levelname)s] %(name)s: %(message)s')
+    try:
+        PostProcessor(**settings).post_process(path)
+        conn.send(None)
+    except Exception as e:
+        conn.send(str(e))
+    conn.close()
+
+def parse_event_name(name, name_template=TIME_FORMAT, label=None):
+    template = name_template.replace('{label}', (label or '').replace(' ', '_').replace('%', '%%'))
+    for candidate in (name, re.sub(r'_\d+$', '', name)):
+        try:
+            return datetime.datetime.strptime(candidate, template)
+        except ValueError:
+            pass
+    return None
+
+def event_time(event_path, name_template=TIME_FORMAT, label=None):
+    start = Capture.load_metadata(event_path).get('start')
+    if isinstance(start, datetime.datetime):
+        return start
+    return parse_event_name(os.path.basename(event_path), name_template, label)
+
+def list_events(capture_paths):
+    events = []
+    for path in capture_paths:
+        if path == None or not os.path.isdir(path):
+            continue
+        for event in os.listdir(path):
+            event_path = os.path.join(os.path.abspath(path), event)
+            if os.path.isdir(event_path):
+                timestamp = event_time(event_path)
+                if timestamp != None:
+                    events.append((timestamp, event_path))
+    return sorted(events)
+
+def trim_events(capture_paths, trim_limit, min_events_kept=0, max_events_kept=None, dry_run=False):
+    logger = getLogger('doorcam.capture')
+    events = list(reversed(list_events(capture_paths)))
+    if len(events) == 0:
+        logger.debug('Did not detect any valid event directories while trimming')
+        return []
+    cutoff = datetime.datetime.now() - datetime.timedelta(days=trim_limit)
+    logger.debug(f'Checking for events before {cutoff.strftime(TIME_FORMAT)}')
+    candidates = []
+    kept = []
+    for i, (timestamp, path) in enumerate(events):
+        if timestamp < cutoff and i >= min_events_kept:
+            candidates.append((path, f'it is older than {cutoff.strftime(TIME_FORMAT)}'))
+        else:
+            kept.append(path)
+    if max_events_kept and len(kept) > max_events_kept:
+        candidates += [(path, f'only the newest {max_events_kept} events are kept') for path in kept[max_events_kept:]]
+    trimmed = []
+    for path, reason in candidates:
+        logger.debug(f'Trimming {path} as {reason}')
+        if not dry_run:
+            try:
+                shutil.rmtree(path)
+            except Exception as e:
+                logger.error(e)
+                continue
+        trimmed.append((path, reason))
+    return trimmed
+
+def find_event(capture_paths, event):
+    for path in capture_paths:
+        if path == None:
+            continue
+        event_path = os.path.join(os.path.abspath(path), os.path.basename(event))
+        if os.path.isdir(event_path) and event_time(event_path) != None:
+            return event_path
+    raise FileNotFoundError(errno.ENOENT, f'No event named {event}', event)
+
+def export_event(event_path, dest):
+    event_path = os.path.abspath(event_path)
+    event = os.path.basename(event_path)
+    if os.path.isdir(dest):
+        dest = os.path.join(dest, event + '.zip')
+    Capture.update_metadata(event_path, {'last_accessed': datetime.datetime.now()})
+    with zipfile.ZipFile(dest, 'w', zipfile.ZIP_DEFLATED) as archive:
+        for root, dirs, files in os.walk(event_path):
+            dirs.sort()
+            for filename in sorted(files):
+                fullpath = os.path.join(root, filename)
+                archive.write(fullpath, os.path.join(event, os.path.relpath(fullpath, event_path)))
+    return dest, os.path.getsize(dest)
+
+def verify_events(capture_paths, repair=False, post_process_retries=None, name_template=TIME_FORMAT, label=None):
+    findings = []
+    for path in capture_paths:
+        if path == None or not os.path.isdir(path):
+            continue
+        for name in sorted(os.listdir(path)):
+            fullpath = os.path.join(path, name)
+            if name in (PREROLL_SPOOL_DIR, DELETED_DIR):
+                continue
+            if os.path.isfile(fullpath):
+                event, ext = os.path.splitext(name)
+                start = parse_event_name(event, name_template, label)
+                if start == None:
+                    findings.append((fullpath, 'unrecognized file'))
+                    continue
+                if ext[1:] not in VIDEO_CODECS or os.path.exists(os.path.join(path, event)):
+                    findings.append((fullpath, 'unrecognized file'))
+                    continue
+                if repair:
+                    os.mkdir(os.path.join(path, event))
+                    shutil.move(fullpath, os.path.join(path, event, name))
+                    Capture.update_metadata(os.path.join(path, event), {'start': start, 'video': name, 'recovered': True})
+                    findings.append((fullpath, 'registered orphaned video'))
+                else:
+                    findings.append((fullpath, 'orphaned video'))
+                continue
+            start = event_time(fullpath, name_template, label)
+            if start == None:
+                findings.append((fullpath, 'unrecognized directory'))
+                continue
+            metadata = Capture.load_metadata(fullpath)
+            has_images = os.path.isdir(os.path.join(fullpath, 'images'))
+            video = metadata.get('video')
+            if has_images and 'start' in metadata and 'frames' not in metadata:
+                attempts = metadata.get('post_process_attempts', 0)
+                if post_process_retries != None and attempts > post_process_retries:
+                    findings.append((fullpath, f'post-processing gave up after {attempts} attempts, its images are kept'))
+                else:
+                    findings.append((fullpath, 'images were never post-processed, they will be queued on next start'))
+                continue
+            if metadata.get('frames') == 0:
+                if repair:
+                    shutil.rmtree(fullpath)
+                    findings.append((fullpath, 'pruned zero-frame event'))
+                else:
+                    findings.append((fullpath, 'zero-frame event'))
+                continue
+            if video == None:
+                video = next((f'{name}.{x}' for x in VIDEO_CODECS if os.path.isfile(os.path.join(fullpath, f'{name}.{x}'))), None)
+                if video != None:
+                    if repair:
+                        Capture.update_metadata(fullpath, {'start': start, 'video': video, 'recovered': True})
+                        findings.append((fullpath, 'registered video without metadata'))
+                    else:
+                        findings.append((fullpath, 'video without metadata'))
+                elif not has_images:
+                    findings.append((fullpath, 'no video or images'))
+                elif len(metadata) == 0:
+                    findings.append((fullpath, 'images without metadata'))
+            elif not os.path.isfile(os.path.join(fullpath, video)):
+                findings.append((fullpath, f'metadata references missing video {video}' + (', images are still present' if has_images else '')))
+            elif os.path.getsize(os.path.join(fullpath, video)) == 0 and not has_images:
+                if repair:
+                    shutil.rmtree(fullpath)
+                    findings.append((fullpath, f'pruned event with empty video {video}'))
+                else:
+                    findings.append((fullpath, f'video {video} is empty'))
+            elif metadata.get('video_size') != None and os.path.getsize(os.path.join(fullpath, video)) != metadata['video_size']:
+                findings.append((fullpath, f'video {video} is {os.path.getsize(os.path.join(fullpath, video))} bytes but metadata recorded {metadata["video_size"]}'))
+    return findings
 
 class CaptureQueue():
 
     logger = getLogger('doorcam.capture.queue')
 
-    def __init__(self, camera: Camera, preroll_time):
+    def __init__(self, camera: Camera, preroll_time, eviction='time', memory_time=None, spool_path=None, memory_budget=None):
         self.camera = camera
         self.preroll = preroll_time
+        self.eviction = eviction
+        self.capacity = None
+        if self.eviction == 'count':
+            self.capacity = int(self.preroll * self.camera.max_fps)
+        elif self.eviction == 'frames':
+            self.capacity = int(self.preroll)
+        self.pushed = 0
+        self.evicted = 0
+        self.memory = memory_time
+        self.spool_path = spool_path
+        self.memory_budget = memory_budget
+        self.memory_bytes = 0
+        self.budget_warned = False
         self.queue = list()
+        self.spooled = list()
+        self.lock = Lock()
+        if self.spool_path != None:
+            if os.path.isdir(self.spool_path):
+                shutil.rmtree(self.spool_path)
+            os.mkdir(self.spool_path)
         self.camera.add_callback(self.push)
 
-    def trim(self, now=time.time()):
-        if len(self.queue) > 0:
-            #self.sort()
-            cutoff = now - self.preroll
-            while self.queue[0][0] < cutoff:
-                self.queue.pop(0)
+    def expired(self, now):
+        if self.capacity != None:
+            return len(self.spooled) + len(self.queue) >= self.capacity
+        oldest = self.spooled[0][0] if len(self.spooled) > 0 else self.queue[0][0]
+        return oldest < now - self.preroll
+
+    def trim(self, now):
+        #self.sort()
+        while len(self.spooled) > 0 and self.expired(now):
+            try:
+                os.remove(self.spooled.pop(0)[1])
+            except OSError as e:
+                self.logger.error(e)
+            self.evicted += 1
+        while len(self.queue) > 0 and self.expired(now):
+            self.memory_bytes -= len(self.queue.pop(0)[1])
+            self.evicted += 1
+        if self.memory != None:
+            spill_cutoff = now - self.memory
+            while len(self.queue) > 0 and self.queue[0][0] < spill_cutoff:
+                self.spill(*self.queue.pop(0))
+        if self.memory_budget != None:
+            while len(self.queue) > 1 and self.memory_bytes > self.memory_budget:
+                timestamp, image = self.queue.pop(0)
+                if self.spool_path != None:
+                    self.spill(timestamp, image)
+                else:
+                    self.memory_bytes -= len(image)
+                    self.evicted += 1
+                    if not self.budget_warned:
+                        self.budget_warned = True
+                        self.logger.warning(f'Preroll memory budget of {self.memory_budget // (1024 * 1024)} MB only holds {len(self.queue)} frames over {now - self.queue[0][0]:.1f}s, shorter than the requested preroll')
+
+    def spill(self, timestamp, image):
+        self.memory_bytes -= len(image)
+        filename = os.path.join(self.spool_path, f'{timestamp:.6f}.jpg')
+        try:
+            with open(filename, 'wb') as out:
+                out.write(image)
+            self.spooled.append((timestamp, filename))
+        except OSError as e:
+            self.logger.error(f'Could not spill preroll frame to {filename}: {e}')
+
+    def frames(self):
+        with self.lock:
+            spooled = self.spooled.copy()
+            queue = self.queue.copy()
+        frames = []
+        for timestamp, filename in spooled:
+            try:
+                frames.append((timestamp, np.fromfile(filename, dtype=np.uint8)))
+            except OSError:
+                self.logger.debug(f'Spilled preroll frame {filename} was trimmed before it could be read')
+        return frames + queue
+
+    def stats(self):
+        with self.lock:
+            oldest = self.spooled[0][0] if len(self.spooled) > 0 else self.queue[0][0] if len(self.queue) > 0 else None
+            newest = self.queue[-1][0] if len(self.queue) > 0 else None
+            return {
+                'length': len(self.spooled) + len(self.queue),
+                'spooled': len(self.spooled),
+                'memory': self.memory_bytes,
+                'capacity': self.capacity,
+                'pushed': self.pushed,
+                'evicted': self.evicted,
+                'span': newest - oldest if newest != None else 0
+            }
     
     def sort(self):
         self.queue.sort(key = lambda x: x[0])
 
     def push(self, image):
-        now = time.time()
-        self.trim(now)
-        self.queue.append((now, image))
\ No newline at end of file
+        now = time.monotonic()
+        with self.lock:
+            self.trim(now)
+            self.queue.append((now, image))
+            self.memory_bytes += len(image)
+            self.pushed += 1
\ No newline at end of file
@@ -1,7 +1,12 @@
 import yaml
 import os
+import re
+import copy
 import cv2
 import numpy as np
+import datetime
+import zoneinfo
+import ipaddress
 from logging import getLogger
 
 DEFAULT_ANALYSIS_DELTA_THRESHOLD=10
@@ -9,12 +14,27 @@ DEFAULT_ANALYSIS_CONTOUR_MIN_AREA=10000
 DEFAULT_ANALYSIS_MAX_FPS=5
 DEFAULT_ANALYSIS_UNDISTORT=True
 DEFAULT_ANALYSIS_UNDISTORT_BALANCE=1.0
+DEFAULT_ANALYSIS_PROFILES=[]
+DEFAULT_ANALYSIS_COOLDOWN=0
+DEFAULT_ANALYSIS_MOTION_END_FRAMES=10
+DEFAULT_ANALYSIS_WARMUP=3
+DEFAULT_ANALYSIS_LEARNING_RATE=0.5
+DEFAULT_ANALYSIS_REJECT_LIGHTING_CHANGES=False
+DEFAULT_ANALYSIS_LIGHTING_CHANGE_THRESHOLD=0.3
+DEFAULT_ANALYSIS_BLUR_SIZE=21
+DEFAULT_ANALYSIS_ERODE_ITERATIONS=0
+DEFAULT_ANALYSIS_DILATE_ITERATIONS=2
 DEFAULT_CAMERA_INDEX=0
 DEFAULT_CAMERA_FORMAT='MJPG'
 DEFAULT_CAMERA_RESOLUTION='1920x1080'
 DEFAULT_CAMERA_ROTATION=None
 DEFAULT_CAMERA_MAX_FPS=30
 DEFAULT_CAMERA_K='[[539.8606873339231, 0.0, 999.745990731636], [0.0, 540.4889507343736, 541.3382370501859], [0.0, 0.0, 1.0]]'
+DEFAULT_CAMERA_RECONNECT_TIMEOUT=5
+DEFAULT_CAMERA_RECONNECT_BACKOFF_MAX=60
+DEFAULT_CAMERA_STALL_SHUTDOWN=300
+DEFAULT_CAMERA_JPEG_QUALITY=None
+DEFAULT_CAMERA_STRICT_FORMAT=False
 DEFAULT_CAMERA_D='[[-0.06300247530706406], [0.028367414247228113], [-0.018682028009339952], [0.0037199220124150604]]'
 DEFAULT_FRAMEBUFFER_DEVICE='/dev/fb0'
 DEFAULT_FRAMEBUFFER_DTYPE='uint16'
@@ -26,36 +46,143 @@ DEFAULT_FRAMEBUFFER_UNDISTORT_BALANCE=1.0
 DEFAULT_BACKLIGHT_DEVICE='/sys/class/backlight/rpi_backlight/bl_power'
 DEFAULT_TOUCH_DEVICE='/dev/input/event1'
 DEFAULT_SCREEN_ACTIVATION_PERIOD = 10
+DEFAULT_SCREEN_SWIPE_DISTANCE = 0.25
+DEFAULT_SCREEN_LONG_PRESS_TIME = 1.0
+DEFAULT_SCREEN_TOUCH_ZONES = []
+DEFAULT_SCREEN_IDLE_SCREEN = False
+DEFAULT_SCREEN_NOTIFICATION_PERIOD = 5
+DEFAULT_SCREEN_FRAME_POLICY = 'latest'
+DEFAULT_SCREEN_WAKE_ON = ['motion', 'touch']
+DEFAULT_SCREEN_MOTION_WAKE_DISARMED = True
+DEFAULT_SCREEN_FADE_TIME = 0
+DEFAULT_SCREEN_BACKLOG_DROP = 'oldest'
+DEFAULT_SCREEN_BACKLOG_BLOCK_TIME = 0.1
 DEFAULT_STREAM_IP = '0.0.0.0'
 DEFAULT_STREAM_PORT = 8080
+DEFAULT_STREAM_MODE = 'mjpeg'
+DEFAULT_STREAM_HLS_PATH = 'hls'
+DEFAULT_STREAM_HLS_SEGMENT_TIME = 2
+DEFAULT_STREAM_HLS_WINDOW = 30
+DEFAULT_STREAM_AUTH_USERNAME = None
+DEFAULT_STREAM_AUTH_PASSWORD = None
+DEFAULT_STREAM_AUTH_TOKEN = None
+DEFAULT_STREAM_ROTATION = None
+DEFAULT_STREAM_MAX_CLIENTS = 4
+DEFAULT_STREAM_RESOLUTIONS = []
+DEFAULT_STREAM_EVENTS_API = False
+DEFAULT_STREAM_ADAPTIVE_QUALITY = False
+DEFAULT_STREAM_ALLOWED_NETWORKS = []
+DEFAULT_STREAM_TLS_CERT = None
+DEFAULT_STREAM_TLS_KEY = None
+DEFAULT_STREAM_FRAME_HEADERS = False
+DEFAULT_STREAM_ADAPTIVE_QUALITY_THRESHOLD = 0.1
+DEFAULT_STREAM_ADAPTIVE_QUALITY_REDUCED = 50
 DEFAULT_CAPTURE_ENABLE = True
 DEFAULT_CAPTURE_KEEP_IMAGES = False
 DEFAULT_CAPTURE_PREROLL = 5
 DEFAULT_CAPTURE_POSTROLL = 5
+DEFAULT_CAPTURE_POSTROLL_EXTENSION_FRACTION = 0
+DEFAULT_CAPTURE_POSTROLL_MAX = None
+DEFAULT_CAPTURE_POSTROLL_SCALE_AREA = 100000
 DEFAULT_CAPTURE_PATH = 'capture'
 DEFAULT_CAPTURE_ROTATION='ROTATE_90_COUNTERCLOCKWISE'
 DEFAULT_CAPTURE_TIMESTAMP = True
 DEFAULT_CAPTURE_TRIM_OLD = True
 DEFUALT_CAPTURE_TRIM_LIMIT = 30
+DEFAULT_CAPTURE_MIN_EVENTS_KEPT = 0
+DEFAULT_CAPTURE_MAX_EVENTS_KEPT = None
 DEFAULT_CAPTURE_VIDEO_ENCODE = True
+DEFAULT_CAPTURE_FSYNC_INTERVAL = 0
+DEFAULT_CAPTURE_FALLBACK_PATH = None
+DEFAULT_CAPTURE_MAX_DURATION = 300
+DEFAULT_CAPTURE_DEDUP = False
+DEFAULT_CAPTURE_DEDUP_THRESHOLD = 0.01
+DEFAULT_CAPTURE_PREROLL_MEMORY = None
+DEFAULT_CAPTURE_PREROLL_MEMORY_BUDGET = None
+DEFAULT_CAPTURE_PREROLL_EVICTION = 'time'
+DEFAULT_CAPTURE_REARM_TIMEOUT = None
+DEFAULT_CAPTURE_ARM_SCHEDULE = []
+DEFAULT_CAPTURE_ARM_TIMEZONE = None
+DEFAULT_CAPTURE_CONTAINER = 'mp4'
+DEFAULT_CAPTURE_ROLL_UNIT = 'seconds'
+DEFAULT_CAPTURE_DRAW_MOTION_BOXES = False
+DEFAULT_CAPTURE_TIMESTAMP_POSITION = 'top_left'
+DEFAULT_CAPTURE_TIMESTAMP_FORMAT = '%H:%M:%S %m/%d/%Y'
+DEFAULT_CAPTURE_TIMESTAMP_UTC = False
+DEFAULT_CAPTURE_TIMESTAMP_COLOR = [255, 255, 255]
+DEFAULT_CAPTURE_LABEL = None
+DEFAULT_CAPTURE_LABEL_POSITION = 'top_right'
+DEFAULT_CAPTURE_LOGO_PATH = None
+DEFAULT_CAPTURE_LOGO_POSITION = 'bottom_right'
+DEFAULT_CAPTURE_ENCODER_CHECK_INTERVAL = 3600
+DEFAULT_CAPTURE_POST_PROCESS_TIMEOUT = 600
+DEFAULT_CAPTURE_POST_PROCESS_RETRIES = 3
+DEFAULT_CAPTURE_POST_PROCESS_WORKERS = 1
+DEFAULT_CAPTURE_KEEP_DELETED = 0
+DEFAULT_CAPTURE_NAME_TEMPLATE = '%Y-%m-%d_%H-%M-%S-%f'
+DEFAULT_CAPTURE_CROP_REGION = None
+DEFAULT_EVENTS_SOCKET_PATH = None
+DEFAULT_EVENTS_QUIET_PERIOD = 0
+DEFAULT_EVENTS_STATS_INTERVAL = 0
+DEFAULT_LOGGING_FILE = None
+DEFAULT_LOGGING_ROTATION = 'daily'
+DEFAULT_LOGGING_MAX_SIZE = 10
+DEFAULT_LOGGING_BACKUPS = 7
+DEFAULT_LOGGING_LEVELS = {}
+DEFAULT_UPLOAD_URL = None
+DEFAULT_UPLOAD_MODE = 'put'
+DEFAULT_UPLOAD_USERNAME = None
+DEFAULT_UPLOAD_PASSWORD = None
+DEFAULT_UPLOAD_DELETE_LOCAL = False
+DEFAULT_UPLOAD_RETRY_MAX = 3600
+DEFAULT_CAMERAS = {}
+
+STREAM_MODES = ('mjpeg', 'hls')
+PREROLL_EVICTIONS = ('time', 'count')
+CONTAINERS = ('mp4', 'mkv', 'webm')
+ROLL_UNITS = ('seconds', 'frames')
+LOG_ROTATIONS = ('daily', 'size')
+FRAME_POLICIES = ('latest', 'all')
+BACKLOG_DROPS = ('oldest', 'newest', 'block')
+WAKE_TRIGGERS = ('motion', 'touch')
+LOG_LEVELS = ('debug', 'info', 'warning', 'error', 'critical')
+UPLOAD_MODES = ('put', 'webdav')
+CAMERA_SECTIONS = ('camera', 'analyzer', 'capture')
+POSITIONS = ('top_left', 'top_right', 'bottom_left', 'bottom_right')
+WEEKDAYS = ('mon', 'tue', 'wed', 'thu', 'fri', 'sat', 'sun')
+ENV_PREFIX = 'DOORCAM_'
 
 class Config(dict):
 
     logger = getLogger('doorcam.config')
 
-    def __init__(self, path, *args, **kwargs):
+    def __init__(self, path, *args, save=True, **kwargs):
         self.logger.debug('Intializing config from file at {path}')
         super().__init__(*args, **kwargs)
         self.path = path
         self.load_defaults()
         if os.path.isfile(path):
             self.load()
-        self.save()
+        if save:
+            self.save()
+        self.load_env()
         self.init_constants()
         self.logger.debug('Config from file {path} has been initialized!')
 
     def init_constants(self):
         self.logger.debug('Intializing constants from file at {path}')
+        self.init_camera_configs()
+        if not 0 < self['analyzer']['learning_rate'] <= 1:
+            raise ImproperLearningRate('learning_rate must be greater than 0 and at most 1')
+        if self['analyzer']['blur_size'] < 1 or self['analyzer']['blur_size'] % 2 == 0:
+            raise ImproperAnalyzerKernel('blur_size must be a positive odd number')
+        if not 0 <= self['analyzer']['delta_threshold'] <= 255:
+            raise ImproperAnalyzerKernel('delta_threshold must be between 0 and 255')
+        if self['analyzer']['erode_iterations'] < 0 or self['analyzer']['dilate_iterations'] < 0:
+            raise ImproperAnalyzerKernel('erode_iterations and dilate_iterations must not be negative')
+        self['analyzer']['profiles_const'] = profiles_to_windows(self['analyzer']['profiles'])
+        if self['camera']['stall_shutdown'] and self['camera']['stall_shutdown'] <= self['camera']['reconnect_timeout']:
+            raise ImproperStallShutdown('stall_shutdown must be longer than reconnect_timeout so the camera gets a chance to reconnect')
         self['camera']['fourcc'] = string_to_fourcc(self['camera']['format'])
         self['camera']['resolution'] = rstring_to_rtuple(self['camera']['resolution'])
         if self['camera']['rotation'] is None:
@@ -72,16 +199,104 @@ class Config(dict):
             self['capture']['rotation_const'] = None
         else:
             self['capture']['rotation_const'] = cstring_to_cvconstant(self['capture']['rotation'])
+        if self['capture']['postroll_max'] != None and self['capture']['postroll_max'] < self['capture']['postroll']:
+            raise ImproperPostroll('postroll_max must not be less than postroll')
+        if self['capture']['post_process_workers'] < 1:
+            raise ImproperPostProcessWorkers('post_process_workers must be at least 1')
+        if not 0 <= self['capture']['postroll_extension_fraction'] <= 1:
+            raise ImproperPostroll('postroll_extension_fraction must be between 0 and 1')
+        self['capture']['arm_schedule_const'] = schedule_to_windows(self['capture']['arm_schedule'])
+        self['capture']['arm_timezone_const'] = string_to_timezone(self['capture']['arm_timezone'])
+        validate_overlay_positions(self['capture'])
+        validate_strftime(self['capture']['timestamp_format'])
+        validate_color(self['capture']['timestamp_color'])
+        validate_name_template(self['capture']['name_template'], self['capture']['label'])
+        validate_crop_region(self['capture']['crop_region'])
+        if self['capture']['roll_unit'] not in ROLL_UNITS:
+            raise ImproperRollUnit(self['capture']['roll_unit'])
+        if self['capture']['container'] not in CONTAINERS:
+            raise ImproperContainer(self['capture']['container'])
+        if self['capture']['preroll_eviction'] not in PREROLL_EVICTIONS:
+            raise ImproperPrerollEviction(self['capture']['preroll_eviction'])
+        if self['capture']['max_events_kept'] and self['capture']['max_events_kept'] < self['capture']['min_events_kept']:
+            raise ImproperEventsKept('max_events_kept must not be less than min_events_kept')
         self['screen']['resolution'] = rstring_to_rtuple(self['screen']['resolution'])
         if self['screen']['rotation'] is None:
             self['screen']['rotation_const'] = None
         else:
             self['screen']['rotation_const'] = cstring_to_cvconstant(self['screen']['rotation'])
+        for zone in self['screen']['touch_zones']:
+            validate_touch_zone(zone)
         self['screen']['color_conv_const'] = cstring_to_cvconstant(self['screen']['color_conv'])
         self['screen']['dtype_np'] = string_to_dtype(self['screen']['dtype'])
+        if self['stream']['rotation'] is None:
+            self['stream']['rotation_const'] = None
+        else:
+            self['stream']['rotation_const'] = cstring_to_cvconstant(self['stream']['rotation'])
+        self['stream']['resolutions_const'] = {x: rstring_to_rtuple(x) for x in self['stream']['resolutions']}
+        self['stream']['allowed_networks_const'] = [string_to_network(x) for x in self['stream']['allowed_networks']]
+        if (self['stream']['auth_username'] == None) != (self['stream']['auth_password'] == None):
+            raise ImproperStreamAuth('auth_username and auth_password must be set together')
+        if self['stream']['tls_key'] != None and self['stream']['tls_cert'] == None:
+            raise ImproperTLS('tls_key is set but tls_cert is not')
+        if self['stream']['mode'].lower() not in STREAM_MODES:
+            raise ImproperStreamMode(self['stream']['mode'])
+        self['stream']['mode'] = self['stream']['mode'].lower()
+        if self['screen']['frame_policy'] not in FRAME_POLICIES:
+            raise ImproperFramePolicy(f'frame_policy must be one of {", ".join(FRAME_POLICIES)}')
+        if self['screen']['backlog_drop'] not in BACKLOG_DROPS:
+            raise ImproperFramePolicy(f'backlog_drop must be one of {", ".join(BACKLOG_DROPS)}')
+        if not self['screen']['backlog_block_time'] > 0:
+            raise ImproperFramePolicy('backlog_block_time must be greater than 0')
+        for trigger in self['screen']['wake_on']:
+            if trigger not in WAKE_TRIGGERS:
+                raise ImproperWakeTrigger(f'{trigger} is not one of {", ".join(WAKE_TRIGGERS)}')
+        if self['logging']['rotation'] not in LOG_ROTATIONS:
+            raise ImproperLogRotation(f'rotation must be one of {", ".join(LOG_ROTATIONS)}')
+        if self['logging']['backups'] < 1:
+            raise ImproperLogRotation('backups must be at least 1, or rotating would delete the log right away')
+        if self['logging']['rotation'] == 'size' and not self['logging']['max_size'] > 0:
+            raise ImproperLogRotation('max_size must be greater than 0')
+        for name, level in self['logging']['levels'].items():
+            if str(level).lower() not in LOG_LEVELS:
+                raise ImproperLogLevel(f'{name}: {level} is not one of {", ".join(LOG_LEVELS)}')
+        if self['upload']['url'] != None and not str(self['upload']['url']).startswith(('http://', 'https://')):
+            raise ImproperUpload('url must be an http:// or https:// URL')
+        if self['upload']['mode'] not in UPLOAD_MODES:
+            raise ImproperUpload(f'mode must be one of {", ".join(UPLOAD_MODES)}')
+        if self['upload']['retry_max'] < 1:
+            raise ImproperUpload('retry_max must be at least 1')
         self.logger.debug('Constants from file {path} has been initialized!')
 
+    def init_camera_configs(self):
+        self.camera_configs = {}
+        for camera_id, overrides in self['cameras'].items():
+            overrides = overrides or {}
+            validate_camera(camera_id, overrides, self)
+            config = Config.__new__(Config)
+            config.path = self.path
+            config.update(copy.deepcopy({x: y for x, y in self.items() if x != 'cameras'}))
+            config['cameras'] = {}
+            for section, values in overrides.items():
+                config[section].update(values)
+            if 'path' not in overrides.get('capture', {}):
+                config['capture']['path'] = f'{os.path.normpath(self["capture"]["path"])}_{camera_id}'
+            if 'fallback_path' not in overrides.get('capture', {}) and self['capture']['fallback_path'] != None:
+                config['capture']['fallback_path'] = f'{os.path.normpath(self["capture"]["fallback_path"])}_{camera_id}'
+            config.init_constants()
+            self.camera_configs[str(camera_id)] = config
+
+    def all_configs(self):
+        return [(None, self)] + list(self.camera_configs.items())
+
+    def capture_paths(self):
+        paths = []
+        for camera_id, config in self.all_configs():
+            paths += [path for path in (config['capture']['path'], config['capture']['fallback_path']) if path != None]
+        return paths
+
     def clear_constants(self):
+        del self['analyzer']['profiles_const']
         del self['camera']['fourcc']
         self['camera']['resolution'] = rtuple_to_rstring(self['camera']['resolution'])
         del self['camera']['rotation_const']
@@ -89,27 +304,61 @@ class Config(dict):
         self['camera']['D'] = str(self['camera']['D'].tolist())
         self['screen']['resolution'] = rtuple_to_rstring(self['screen']['resolution'])
         del self['capture']['rotation_const']
+        del self['capture']['arm_schedule_const']
+        del self['capture']['arm_timezone_const']
         del self['screen']['rotation_const']
         del self['screen']['color_conv_const']
         del self['screen']['dtype_np']
+        del self['stream']['rotation_const']
+        del self['stream']['resolutions_const']
+        del self['stream']['allowed_networks_const']
         self.logger.debug('Constants from file {path} has been cleared!')
 
 
     def load(self):
         with open(self.path, 'r') as stream:
             try:
-                self.update(yaml.safe_load(stream).copy())
+                for section, values in yaml.safe_load(stream).items():
+                    if type(values) == dict and type(self.get(section)) == dict:
+                        self[section].update(values)
+                    else:
+                        self[section] = values
             except yaml.YAMLError as e:
                 self.logger.error(e)
         self.logger.debug(f'Loaded config from {self.path}')
     
+    def load_env(self):
+        for name, value in os.environ.items():
+            if not name.startswith(ENV_PREFIX):
+                continue
+            name = name[len(ENV_PREFIX):].lower()
+            section = next((x for x in self if name.startswith(f'{x}_')), None)
+            key = None
+            if section != None:
+                key = next((x for x in self[section] if x.lower() == name[len(section) + 1:]), None)
+            if key == None:
+                self.logger.warning(f'{ENV_PREFIX}{name.upper()} does not match any config field, ignoring')
+                continue
+            self[section][key] = yaml.safe_load(value)
+            self.logger.debug(f'Overrode {section}.{key} from {ENV_PREFIX}{name.upper()}')
+
     def load_defaults(self):
         analysis_configs = {
             'delta_threshold': DEFAULT_ANALYSIS_DELTA_THRESHOLD,
             'contour_minimum_area': DEFAULT_ANALYSIS_CONTOUR_MIN_AREA,
             'max_fps': DEFAULT_ANALYSIS_MAX_FPS,
             'undistort': DEFAULT_ANALYSIS_UNDISTORT,
-            'undistort_balance': DEFAULT_ANALYSIS_UNDISTORT_BALANCE
+            'undistort_balance': DEFAULT_ANALYSIS_UNDISTORT_BALANCE,
+            'profiles': DEFAULT_ANALYSIS_PROFILES,
+            'cooldown': DEFAULT_ANALYSIS_COOLDOWN,
+            'motion_end_frames': DEFAULT_ANALYSIS_MOTION_END_FRAMES,
+            'warmup': DEFAULT_ANALYSIS_WARMUP,
+            'learning_rate': DEFAULT_ANALYSIS_LEARNING_RATE,
+            'reject_lighting_changes': DEFAULT_ANALYSIS_REJECT_LIGHTING_CHANGES,
+            'lighting_change_threshold': DEFAULT_ANALYSIS_LIGHTING_CHANGE_THRESHOLD,
+            'blur_size': DEFAULT_ANALYSIS_BLUR_SIZE,
+            'erode_iterations': DEFAULT_ANALYSIS_ERODE_ITERATIONS,
+            'dilate_iterations': DEFAULT_ANALYSIS_DILATE_ITERATIONS
         }
         self.setdefault('analyzer', analysis_configs)
         camera_configs = {
@@ -120,6 +369,11 @@ class Config(dict):
             'max_fps': DEFAULT_CAMERA_MAX_FPS,
             'K': DEFAULT_CAMERA_K,
             'D': DEFAULT_CAMERA_D,
+            'reconnect_timeout': DEFAULT_CAMERA_RECONNECT_TIMEOUT,
+            'reconnect_backoff_max': DEFAULT_CAMERA_RECONNECT_BACKOFF_MAX,
+            'stall_shutdown': DEFAULT_CAMERA_STALL_SHUTDOWN,
+            'jpeg_quality': DEFAULT_CAMERA_JPEG_QUALITY,
+            'strict_format': DEFAULT_CAMERA_STRICT_FORMAT
         }
         self.setdefault('camera', camera_configs)
         screen_configs = {
@@ -132,12 +386,41 @@ class Config(dict):
             'color_conv': DEFAULT_FRAMEBUFFER_COLOR_CONV,
             'activation_period': DEFAULT_SCREEN_ACTIVATION_PERIOD,
             'undistort': DEFAULT_FRAMEBUFFER_UNDISTORT,
-            'undistort_balance': DEFAULT_FRAMEBUFFER_UNDISTORT_BALANCE
+            'undistort_balance': DEFAULT_FRAMEBUFFER_UNDISTORT_BALANCE,
+            'swipe_distance': DEFAULT_SCREEN_SWIPE_DISTANCE,
+            'long_press_time': DEFAULT_SCREEN_LONG_PRESS_TIME,
+            'touch_zones': DEFAULT_SCREEN_TOUCH_ZONES,
+            'idle_screen': DEFAULT_SCREEN_IDLE_SCREEN,
+            'notification_period': DEFAULT_SCREEN_NOTIFICATION_PERIOD,
+            'frame_policy': DEFAULT_SCREEN_FRAME_POLICY,
+            'wake_on': DEFAULT_SCREEN_WAKE_ON,
+            'motion_wake_disarmed': DEFAULT_SCREEN_MOTION_WAKE_DISARMED,
+            'fade_time': DEFAULT_SCREEN_FADE_TIME,
+            'backlog_drop': DEFAULT_SCREEN_BACKLOG_DROP,
+            'backlog_block_time': DEFAULT_SCREEN_BACKLOG_BLOCK_TIME
         }
         self.setdefault('screen', screen_configs)
         stream_configs = {
             'ip': DEFAULT_STREAM_IP,
-            'port': DEFAULT_STREAM_PORT
+            'port': DEFAULT_STREAM_PORT,
+            'mode': DEFAULT_STREAM_MODE,
+            'hls_path': DEFAULT_STREAM_HLS_PATH,
+            'hls_segment_time': DEFAULT_STREAM_HLS_SEGMENT_TIME,
+            'hls_window': DEFAULT_STREAM_HLS_WINDOW,
+            'auth_username': DEFAULT_STREAM_AUTH_USERNAME,
+            'auth_password': DEFAULT_STREAM_AUTH_PASSWORD,
+            'auth_token': DEFAULT_STREAM_AUTH_TOKEN,
+            'rotation': DEFAULT_STREAM_ROTATION,
+            'max_clients': DEFAULT_STREAM_MAX_CLIENTS,
+            'resolutions': DEFAULT_STREAM_RESOLUTIONS,
+            'events_api': DEFAULT_STREAM_EVENTS_API,
+            'adaptive_quality': DEFAULT_STREAM_ADAPTIVE_QUALITY,
+            'adaptive_quality_threshold': DEFAULT_STREAM_ADAPTIVE_QUALITY_THRESHOLD,
+            'adaptive_quality_reduced': DEFAULT_STREAM_ADAPTIVE_QUALITY_REDUCED,
+            'allowed_networks': DEFAULT_STREAM_ALLOWED_NETWORKS,
+            'tls_cert': DEFAULT_STREAM_TLS_CERT,
+            'tls_key': DEFAULT_STREAM_TLS_KEY,
+            'frame_headers': DEFAULT_STREAM_FRAME_HEADERS
         }
         self.setdefault('stream', stream_configs)
         capture_configs = {
@@ -145,14 +428,72 @@ class Config(dict):
             'keep_images': DEFAULT_CAPTURE_KEEP_IMAGES,
             'preroll': DEFAULT_CAPTURE_PREROLL,
             'postroll': DEFAULT_CAPTURE_POSTROLL,
+            'postroll_extension_fraction': DEFAULT_CAPTURE_POSTROLL_EXTENSION_FRACTION,
+            'postroll_max': DEFAULT_CAPTURE_POSTROLL_MAX,
+            'postroll_scale_area': DEFAULT_CAPTURE_POSTROLL_SCALE_AREA,
             'path': DEFAULT_CAPTURE_PATH,
             'rotation': DEFAULT_CAPTURE_ROTATION,
             'timestamp': DEFAULT_CAPTURE_TIMESTAMP,
             'trim_old': DEFAULT_CAPTURE_TRIM_OLD,
             'trim_limit': DEFUALT_CAPTURE_TRIM_LIMIT,
-            'video_encode': DEFAULT_CAPTURE_VIDEO_ENCODE
+            'min_events_kept': DEFAULT_CAPTURE_MIN_EVENTS_KEPT,
+            'max_events_kept': DEFAULT_CAPTURE_MAX_EVENTS_KEPT,
+            'video_encode': DEFAULT_CAPTURE_VIDEO_ENCODE,
+            'fsync_interval': DEFAULT_CAPTURE_FSYNC_INTERVAL,
+            'fallback_path': DEFAULT_CAPTURE_FALLBACK_PATH,
+            'max_duration': DEFAULT_CAPTURE_MAX_DURATION,
+            'dedup': DEFAULT_CAPTURE_DEDUP,
+            'dedup_threshold': DEFAULT_CAPTURE_DEDUP_THRESHOLD,
+            'preroll_memory': DEFAULT_CAPTURE_PREROLL_MEMORY,
+            'preroll_memory_budget': DEFAULT_CAPTURE_PREROLL_MEMORY_BUDGET,
+            'preroll_eviction': DEFAULT_CAPTURE_PREROLL_EVICTION,
+            'rearm_timeout': DEFAULT_CAPTURE_REARM_TIMEOUT,
+            'arm_schedule': DEFAULT_CAPTURE_ARM_SCHEDULE,
+            'arm_timezone': DEFAULT_CAPTURE_ARM_TIMEZONE,
+            'container': DEFAULT_CAPTURE_CONTAINER,
+            'roll_unit': DEFAULT_CAPTURE_ROLL_UNIT,
+            'draw_motion_boxes': DEFAULT_CAPTURE_DRAW_MOTION_BOXES,
+            'timestamp_position': DEFAULT_CAPTURE_TIMESTAMP_POSITION,
+            'timestamp_format': DEFAULT_CAPTURE_TIMESTAMP_FORMAT,
+            'timestamp_utc': DEFAULT_CAPTURE_TIMESTAMP_UTC,
+            'timestamp_color': DEFAULT_CAPTURE_TIMESTAMP_COLOR,
+            'label': DEFAULT_CAPTURE_LABEL,
+            'label_position': DEFAULT_CAPTURE_LABEL_POSITION,
+            'logo_path': DEFAULT_CAPTURE_LOGO_PATH,
+            'logo_position': DEFAULT_CAPTURE_LOGO_POSITION,
+            'encoder_check_interval': DEFAULT_CAPTURE_ENCODER_CHECK_INTERVAL,
+            'keep_deleted': DEFAULT_CAPTURE_KEEP_DELETED,
+            'name_template': DEFAULT_CAPTURE_NAME_TEMPLATE,
+            'crop_region': DEFAULT_CAPTURE_CROP_REGION,
+            'post_process_timeout': DEFAULT_CAPTURE_POST_PROCESS_TIMEOUT,
+            'post_process_retries': DEFAULT_CAPTURE_POST_PROCESS_RETRIES,
+            'post_process_workers': DEFAULT_CAPTURE_POST_PROCESS_WORKERS
         }
         self.setdefault('capture', capture_configs)
+        events_configs = {
+            'socket_path': DEFAULT_EVENTS_SOCKET_PATH,
+            'quiet_period': DEFAULT_EVENTS_QUIET_PERIOD,
+            'stats_interval': DEFAULT_EVENTS_STATS_INTERVAL
+        }
+        self.setdefault('events', events_configs)
+        logging_configs = {
+            'file': DEFAULT_LOGGING_FILE,
+            'rotation': DEFAULT_LOGGING_ROTATION,
+            'max_size': DEFAULT_LOGGING_MAX_SIZE,
+            'backups': DEFAULT_LOGGING_BACKUPS,
+            'levels': DEFAULT_LOGGING_LEVELS
+        }
+        self.setdefault('logging', logging_configs)
+        upload_configs = {
+            'url': DEFAULT_UPLOAD_URL,
+            'mode': DEFAULT_UPLOAD_MODE,
+            'username': DEFAULT_UPLOAD_USERNAME,
+            'password': DEFAULT_UPLOAD_PASSWORD,
+            'delete_local': DEFAULT_UPLOAD_DELETE_LOCAL,
+            'retry_max': DEFAULT_UPLOAD_RETRY_MAX
+        }
+        self.setdefault('upload', upload_configs)
+        self.setdefault('cameras', dict(DEFAULT_CAMERAS))
     
     def save(self):
         with open(self.path, 'w') as stream:
@@ -188,6 +529,134 @@ def string_to_fourcc(format:str):
     else:
         return cv2.VideoWriter_fourcc(*format)
 
+def string_to_time(time_string:str):
+    try:
+        return datetime.datetime.strptime(str(time_string), '%H:%M').time()
+    except ValueError as e:
+        raise ImproperTimeString(e)
+
+def time_in_window(now:datetime.time, start:datetime.time, end:datetime.time):
+    if start < end:
+        return start <= now < end
+    else:
+        return now >= start or now < end
+
+def windows_overlap(a:tuple, b:tuple):
+    def minutes(window):
+        start = window[0].hour * 60 + window[0].minute
+        end = window[1].hour * 60 + window[1].minute
+        if start < end:
+            return [(start, end)]
+        else:
+            return [(start, 24 * 60), (0, end)]
+    return any(x[0] < y[1] and y[0] < x[1] for x in minutes(a) for y in minutes(b))
+
+def profiles_to_windows(profiles:list):
+    windows = []
+    for profile in profiles:
+        start = string_to_time(profile['start'])
+        end = string_to_time(profile['end'])
+        if start == end:
+            raise ImproperSchedule(f'Profile {profile.get("name")} starts and ends at the same time')
+        overrides = {x: profile[x] for x in ('delta_threshold', 'contour_minimum_area') if x in profile}
+        for other in windows:
+            if windows_overlap((start, end), (other['start'], other['end'])):
+                raise ImproperSchedule(f'Profile {profile.get("name")} overlaps profile {other["name"]}')
+        windows.append({'name': profile.get('name', f'{profile["start"]}-{profile["end"]}'), 'start': start, 'end': end, **overrides})
+    return windows
+
+def schedule_to_windows(schedule:list):
+    windows = []
+    for window in schedule:
+        start = string_to_time(window['start'])
+        end = string_to_time(window['end'])
+        if start == end:
+            raise ImproperSchedule(f'Arm window {window["start"]}-{window["end"]} starts and ends at the same time')
+        days = window.get('days', WEEKDAYS)
+        for day in days:
+            if str(day).lower() not in WEEKDAYS:
+                raise ImproperSchedule(f'Arm window {window["start"]}-{window["end"]} has an unknown day {day}, expected one of {", ".join(WEEKDAYS)}')
+        windows.append({'start': start, 'end': end, 'days': set(WEEKDAYS.index(str(x).lower()) for x in days)})
+    return windows
+
+def string_to_timezone(timezone:str):
+    if timezone == None:
+        return None
+    try:
+        return zoneinfo.ZoneInfo(timezone)
+    except (zoneinfo.ZoneInfoNotFoundError, ValueError) as e:
+        raise ImproperTimezone(e)
+
+def validate_strftime(time_format:str):
+    try:
+        sample = datetime.datetime(2000, 1, 2, 3, 4, 5).strftime(str(time_format))
+    except ValueError as e:
+        raise ImproperOverlay(f'{time_format} is not a valid strftime format: {e}')
+    if sample == time_format:
+        raise ImproperOverlay(f'{time_format} does not contain any strftime fields')
+
+def validate_name_template(template:str, label:str=None):
+    sample = datetime.datetime(2000, 1, 2, 3, 4, 5).strftime(str(template))
+    if sample == str(template):
+        raise ImproperNameTemplate(f'{template} does not contain any strftime fields')
+    name = sample.replace('{label}', '')
+    if '/' in name or os.sep in name or name.startswith('.'):
+        raise ImproperNameTemplate(f'{template} must give a plain directory name')
+    name = sample.replace('{label}', str(label or '').replace(' ', '_'))
+    if '/' in name or os.sep in name or name.startswith('.'):
+        raise ImproperNameTemplate(f'{template} with label {label} must give a plain directory name, the label must not contain path separators')
+
+def validate_camera(camera_id, overrides, config):
+    if not re.fullmatch(r'[\w-]+', str(camera_id)):
+        raise ImproperCamera(f'{camera_id} must be a plain name of letters, digits, - and _')
+    if type(overrides) != dict:
+        raise ImproperCamera(f'{camera_id} must map sections to the settings it overrides')
+    for section, values in overrides.items():
+        if section not in CAMERA_SECTIONS:
+            raise ImproperCamera(f'{camera_id}: {section} is not one of {", ".join(CAMERA_SECTIONS)}')
+        if type(values) != dict:
+            raise ImproperCamera(f'{camera_id}: {section} must map settings to values')
+        for key in values:
+            if key not in config[section]:
+                raise ImproperCamera(f'{camera_id}: {section}.{key} is not a setting')
+
+def string_to_network(network:str):
+    try:
+        return ipaddress.ip_network(str(network), strict=False)
+    except ValueError:
+        raise ImproperAllowedNetwork(f'{network} is not an IP address or network, i.e. 192.168.1.0/24')
+
+def validate_crop_region(region:list):
+    if region == None:
+        return
+    if type(region) != list or len(region) != 4 or not all(type(x) in (int, float) for x in region):
+        raise ImproperCropRegion(f'{region} is not a region, expected [x, y, width, height] as fractions of the frame')
+    x, y, width, height = region
+    if x < 0 or y < 0 or width <= 0 or height <= 0 or x + width > 1 or y + height > 1:
+        raise ImproperCropRegion(f'{region} is not within the frame')
+
+def validate_overlay_positions(capture:dict):
+    overlays = {'timestamp': capture['timestamp'], 'label': capture['label'] != None, 'logo': capture['logo_path'] != None}
+    used = {}
+    for overlay, enabled in overlays.items():
+        position = capture[f'{overlay}_position']
+        if position not in POSITIONS:
+            raise ImproperOverlay(f'{overlay}_position must be one of {", ".join(POSITIONS)}')
+        if enabled:
+            if position in used:
+                raise ImproperOverlay(f'The {overlay} and {used[position]} overlays are both in the {position} corner')
+            used[position] = overlay
+
+def validate_color(color:list):
+    if type(color) != list or len(color) != 3 or not all(type(x) == int and 0 <= x <= 255 for x in color):
+        raise ImproperOverlay(f'{color} is not a color, expected [red, green, blue] from 0 to 255')
+
+def validate_touch_zone(zone:dict):
+    if not all(x in zone for x in ('name', 'x', 'y', 'width', 'height')):
+        raise ImproperTouchZone(f'Touch zone {zone} needs a name, x, y, width and height')
+    if zone['x'] < 0 or zone['y'] < 0 or zone['width'] <= 0 or zone['height'] <= 0 or zone['x'] + zone['width'] > 1 or zone['y'] + zone['height'] > 1:
+        raise ImproperTouchZone(f'Touch zone {zone["name"]} is not within the screen')
+
 def string_to_dtype(dtype:int):
     try:
         return(eval(f'np.{dtype.lower()}'))
@@ -207,4 +676,82 @@ class ImproperNPDType(Exception):
     pass
 
 class ImproperFourCCString(Exception):
-    pass
\ No newline at end of file
+    pass
+
+class ImproperStreamMode(Exception):
+    pass
+
+class ImproperStallShutdown(Exception):
+    pass
+
+class ImproperPostroll(Exception):
+    pass
+
+class ImproperEventsKept(Exception):
+    pass
+
+class ImproperPrerollEviction(Exception):
+    pass
+
+class ImproperContainer(Exception):
+    pass
+
+class ImproperRollUnit(Exception):
+    pass
+
+class ImproperOverlay(Exception):
+    pass
+
+class ImproperCropRegion(Exception):
+    pass
+
+class ImproperTouchZone(Exception):
+    pass
+
+class ImproperTimeString(Exception):
+    pass
+
+class ImproperSchedule(Exception):
+    pass
+
+class ImproperTimezone(Exception):
+    pass
+
+class ImproperLearningRate(Exception):
+    pass
+
+class ImproperAnalyzerKernel(Exception):
+    pass
+
+class ImproperNameTemplate(Exception):
+    pass
+
+class ImproperLogRotation(Exception):
+    pass
+
+class ImproperLogLevel(Exception):
+    pass
+
+class ImproperFramePolicy(Exception):
+    pass
+
+class ImproperWakeTrigger(Exception):
+    pass
+
+class ImproperUpload(Exception):
+    pass
+
+class ImproperPostProcessWorkers(Exception):
+    pass
+
+class ImproperAllowedNetwork(Exception):
+    pass
+
+class ImproperTLS(Exception):
+    pass
+
+class ImproperCamera(Exception):
+    pass
+
+class ImproperStreamAuth(Exception):
+    pass
@@ -0,0 +1,122 @@
+import cv2
+import numpy as np
+import os
+import shutil
+import tempfile
+from threading import Thread
+from evdev import InputDevice
+from doorcapture import VIDEO_CODECS
+
+CHECK_TIMEOUT = 10
+ENCODE_TEST_FRAMES = 5
+ENCODE_TEST_RESOLUTION = (320, 240)
+
+def check_camera(config):
+    cap = cv2.VideoCapture(config['camera']['index'], cv2.CAP_V4L2)
+    try:
+        if not cap.isOpened():
+            raise Exception(f'Could not open /dev/video{config["camera"]["index"]}')
+        cap.set(cv2.CAP_PROP_FOURCC, config['camera']['fourcc'])
+        cap.set(cv2.CAP_PROP_FRAME_WIDTH, config['camera']['resolution'][0])
+        cap.set(cv2.CAP_PROP_FRAME_HEIGHT, config['camera']['resolution'][1])
+        ret, frame = cap.read()
+        if not ret:
+            raise Exception('Opened but could not read a frame')
+        return f'Read a {int(cap.get(cv2.CAP_PROP_FRAME_WIDTH))}x{int(cap.get(cv2.CAP_PROP_FRAME_HEIGHT))} frame'
+    finally:
+        cap.release()
+
+def check_capture_path(config):
+    path = os.path.abspath(config['capture']['path'])
+    if not os.path.isdir(path):
+        os.mkdir(path)
+    data = os.urandom(1024)
+    with tempfile.NamedTemporaryFile(dir=path) as test_file:
+        test_file.write(data)
+        test_file.flush()
+        with open(test_file.name, 'rb') as check:
+            if check.read() != data:
+                raise Exception('Read back different data than was written')
+    return f'{shutil.disk_usage(path).free // (1024 * 1024)} MB free at {path}'
+
+def check_framebuffer(config):
+    with open(config['screen']['framebuffer_device'], 'rb+'):
+        pass
+    return f'Opened {config["screen"]["framebuffer_device"]}'
+
+def check_backlight(config):
+    with open(config['screen']['backlight_device'], 'rb') as backlight:
+        state = backlight.read().strip()
+    if not os.access(config['screen']['backlight_device'], os.W_OK):
+        raise Exception(f'{config["screen"]["backlight_device"]} is not writable')
+    return f'Backlight power is {state.decode()}'
+
+def check_touch(config):
+    dev = InputDevice(config['screen']['touch_device'])
+    try:
+        return f'Opened {dev.name}'
+    finally:
+        dev.close()
+
+def check_encoder(config):
+    with tempfile.TemporaryDirectory() as path:
+        container = config['capture']['container']
+        video_file = os.path.join(path, f'test.{container}')
+        video_writer = cv2.VideoWriter(video_file, cv2.VideoWriter_fourcc(*VIDEO_CODECS[container]), 5, ENCODE_TEST_RESOLUTION)
+        if not video_writer.isOpened():
+            raise Exception('Could not open video writer')
+        for i in range(ENCODE_TEST_FRAMES):
+            video_writer.write(np.full((ENCODE_TEST_RESOLUTION[1], ENCODE_TEST_RESOLUTION[0], 3), i * 40, dtype=np.uint8))
+        video_writer.release()
+        size = os.path.getsize(video_file)
+        if size == 0:
+            raise Exception('Encoded video is empty')
+        return f'Encoded {ENCODE_TEST_FRAMES} frames to {size} bytes'
+
+def check_ffmpeg(config):
+    if config['stream']['mode'] != 'hls':
+        return 'Not needed, stream mode is mjpeg'
+    if shutil.which('ffmpeg') == None:
+        raise Exception('ffmpeg is not installed')
+    return f'Found {shutil.which("ffmpeg")}'
+
+CHECKS = (
+    ('Camera', check_camera, True),
+    ('Capture path', check_capture_path, True),
+    ('Video encoder', check_encoder, True),
+    ('Framebuffer', check_framebuffer, False),
+    ('Backlight', check_backlight, False),
+    ('Touchscreen', check_touch, False),
+    ('ffmpeg', check_ffmpeg, False)
+)
+
+def run_check(check, config):
+    result = {}
+    def target():
+        try:
+            result['message'] = check(config)
+            result['passed'] = True
+        except Exception as e:
+            result['message'] = str(e)
+            result['passed'] = False
+    thread = Thread(target=target, daemon=True)
+    thread.start()
+    thread.join(CHECK_TIMEOUT)
+    if thread.is_alive():
+        return False, f'Timed out after {CHECK_TIMEOUT} seconds'
+    return result['passed'], result['message']
+
+def run_diagnostics(config):
+    passed = True
+    width = max(len(x[0]) for x in CHECKS)
+    for name, check, critical in CHECKS:
+        ok, message = run_check(check, config)
+        if ok:
+            status = 'PASS'
+        elif critical:
+            status = 'FAIL'
+            passed = False
+        else:
+            status = 'WARN'
+        print(f'{name.ljust(width)}  {status}  {message}')
+    return passed
@@ -0,0 +1,186 @@
+import socket
+import os
+import json
+import time
+import datetime
+from threading import Thread, Lock
+from logging import getLogger
+from doorconfig import string_to_time, time_in_window
+
+EVENT_SEND_TIMEOUT = 0.1
+THROTTLE_CHECK_INTERVAL = 0.5
+COALESCED_EVENTS = ('motion_detected', 'capture_started', 'motion_ended')
+FILTER_MAX_LENGTH = 4096
+
+def parse_filter(raw:dict):
+    if not isinstance(raw, dict):
+        raise ValueError('filter must be a JSON object')
+    event_filter = {}
+    if 'types' in raw:
+        if not isinstance(raw['types'], list):
+            raise ValueError('types must be a list')
+        event_filter['types'] = set(raw['types'])
+    if 'start' in raw or 'end' in raw:
+        event_filter['start'] = string_to_time(raw.get('start'))
+        event_filter['end'] = string_to_time(raw.get('end'))
+    for key in ('and', 'or'):
+        if key in raw:
+            if not isinstance(raw[key], list):
+                raise ValueError(f'{key} must be a list of filters')
+            event_filter[key] = [parse_filter(x) for x in raw[key]]
+    if 'not' in raw:
+        event_filter['not'] = parse_filter(raw['not'])
+    return event_filter
+
+def filter_matches(event_filter:dict, event_type:str, timestamp:float):
+    if 'types' in event_filter and event_type not in event_filter['types']:
+        return False
+    if 'start' in event_filter and not time_in_window(datetime.datetime.fromtimestamp(timestamp).time(), event_filter['start'], event_filter['end']):
+        return False
+    if 'and' in event_filter and not all(filter_matches(x, event_type, timestamp) for x in event_filter['and']):
+        return False
+    if 'or' in event_filter and not any(filter_matches(x, event_type, timestamp) for x in event_filter['or']):
+        return False
+    if 'not' in event_filter and filter_matches(event_filter['not'], event_type, timestamp):
+        return False
+    return True
+
+class EventSocket():
+
+    logger = getLogger('doorcam.events')
+
+    def __init__(self, path, quiet_period=0):
+        self.logger.debug(f'Initializing event socket at {path}')
+        self.path = os.path.abspath(path)
+        self.clients = []
+        self.filters = {}
+        self.subscribers = {}
+        self.lock = Lock()
+        self.quiet_period = quiet_period
+        self.motion_start = None
+        self.quiet_until = None
+        self.throttle_lock = Lock()
+        if os.path.exists(self.path):
+            os.remove(self.path)
+        self.server = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        self.server.bind(self.path)
+        self.server.listen()
+        self.accept_thread = Thread(target=self.accept_loop, daemon=True)
+        self.accept_thread.start()
+        if self.quiet_period:
+            self.throttle_thread = Thread(target=self.throttle_loop, daemon=True)
+            self.throttle_thread.start()
+        self.logger.info(f'Publishing events on {self.path}')
+
+    def accept_loop(self):
+        while True:
+            try:
+                client, address = self.server.accept()
+            except OSError:
+                break
+            client.settimeout(EVENT_SEND_TIMEOUT)
+            with self.lock:
+                self.clients.append(client)
+            Thread(target=self.filter_loop, args=(client, ), daemon=True).start()
+            self.logger.debug(f'Event client connected, {len(self.clients)} connected')
+
+    def filter_loop(self, client):
+        buffer = b''
+        while client in self.clients:
+            try:
+                data = client.recv(FILTER_MAX_LENGTH)
+            except socket.timeout:
+                continue
+            except OSError:
+                break
+            if not data:
+                break
+            buffer += data
+            while b'\n' in buffer:
+                line, buffer = buffer.split(b'\n', 1)
+                try:
+                    event_filter = parse_filter(json.loads(line))
+                except Exception as e:
+                    self.send_to(client, 'filter_error', message=str(e))
+                    continue
+                with self.lock:
+                    self.filters[client] = event_filter
+                self.logger.debug(f'Event client set filter {event_filter}')
+            if len(buffer) > FILTER_MAX_LENGTH:
+                buffer = b''
+
+    def publish(self, event_type:str, **values):
+        if self.quiet_period and event_type in COALESCED_EVENTS:
+            self.coalesce(event_type, values)
+        else:
+            self.send(event_type, **values)
+
+    def coalesce(self, event_type:str, values:dict):
+        if event_type == 'motion_ended':
+            return
+        with self.throttle_lock:
+            now = time.time()
+            started = self.motion_start == None
+            if started:
+                self.motion_start = now
+            self.quiet_until = now + self.quiet_period
+        if started:
+            self.send('motion_started', cause=event_type, **values)
+
+    def throttle_loop(self):
+        while True:
+            duration = None
+            with self.throttle_lock:
+                if self.motion_start != None and time.time() >= self.quiet_until:
+                    duration = self.quiet_until - self.quiet_period - self.motion_start
+                    self.motion_start = None
+            if duration != None:
+                self.send('motion_ended', duration=duration)
+            time.sleep(THROTTLE_CHECK_INTERVAL)
+
+    def send(self, event_type:str, **values):
+        timestamp = time.time()
+        data = (json.dumps({'type': event_type, 'timestamp': timestamp, **values}, default=str) + '\n').encode()
+        with self.lock:
+            for client in self.clients.copy():
+                if client in self.filters and not filter_matches(self.filters[client], event_type, timestamp):
+                    continue
+                self.send_data(client, data)
+            for callback, event_filter in self.subscribers.items():
+                if event_filter == None or filter_matches(event_filter, event_type, timestamp):
+                    callback(data)
+
+    def subscribe(self, callback, event_filter:dict=None):
+        with self.lock:
+            self.subscribers[callback] = event_filter
+
+    def unsubscribe(self, callback):
+        with self.lock:
+            self.subscribers.pop(callback, None)
+
+    def send_to(self, client, event_type:str, **values):
+        data = (json.dumps({'type': event_type, 'timestamp': time.time(), **values}, default=str) + '\n').encode()
+        with self.lock:
+            if client in self.clients:
+                self.send_data(client, data)
+
+    def send_data(self, client, data):
+        try:
+            client.sendall(data)
+        except OSError as e:
+            self.logger.info(f'Dropping event client: {e}')
+            self.clients.remove(client)
+            self.filters.pop(client, None)
+            client.close()
+
+    def close(self):
+        with self.lock:
+            for client in self.clients:
+                client.close()
+            self.clients = []
+            self.filters = {}
+        self.server.close()
+        try:
+            os.remove(self.path)
+        except OSError as e:
+            self.logger.error(e)
@@ -0,0 +1,113 @@
+import cv2
+import os
+import shutil
+import time
+from threading import Thread
+from logging import getLogger
+from doorcapture import DELETED_DIR, Capture, list_events, find_event
+from doortimelapse import event_frames
+
+THUMBNAIL_FILE = 'thumbnail.jpg'
+THUMBNAIL_WIDTH = 320
+DEFAULT_PAGE_SIZE = 50
+PURGE_INTERVAL = 3600
+
+def event_size(event_path):
+    size = 0
+    for root, dirs, files in os.walk(event_path):
+        size += sum(os.path.getsize(os.path.join(root, x)) for x in files)
+    return size
+
+class EventLibrary():
+
+    logger = getLogger('doorcam.library')
+
+    def __init__(self, capture_paths, captures=(), keep_deleted=0):
+        self.capture_paths = capture_paths
+        self.captures = captures
+        self.keep_deleted = keep_deleted
+        if self.keep_deleted:
+            self.purge_thread = Thread(target=self.purge_loop, daemon=True)
+            self.purge_thread.start()
+
+    def list(self, offset=0, limit=DEFAULT_PAGE_SIZE):
+        events = list(reversed(list_events(self.capture_paths)))
+        return {
+            'total': len(events),
+            'offset': offset,
+            'events': [self.describe(path) for timestamp, path in events[offset:offset + limit]]
+        }
+
+    def describe(self, event_path):
+        metadata = Capture.load_metadata(event_path)
+        return {'id': os.path.basename(event_path), 'size': event_size(event_path), **metadata}
+
+    def usage(self):
+        days = {}
+        total = 0
+        events = list_events(self.capture_paths)
+        for timestamp, path in events:
+            size = event_size(path)
+            day = days.setdefault(timestamp.date().isoformat(), {'events': 0, 'size': 0})
+            day['events'] += 1
+            day['size'] += size
+            total += size
+        return {'events': len(events), 'size': total, 'days': days}
+
+    def get(self, event):
+        return self.describe(find_event(self.capture_paths, event))
+
+    def video_path(self, event):
+        event_path = find_event(self.capture_paths, event)
+        video = Capture.load_metadata(event_path).get('video')
+        if video == None or not os.path.isfile(os.path.join(event_path, video)):
+            raise FileNotFoundError(f'Event {event} has no video')
+        return os.path.join(event_path, video)
+
+    def thumbnail_path(self, event):
+        event_path = find_event(self.capture_paths, event)
+        thumbnail = os.path.join(event_path, THUMBNAIL_FILE)
+        if not os.path.isfile(thumbnail):
+            timestamp, image = next(event_frames(event_path), (None, None))
+            if image is None:
+                raise FileNotFoundError(f'Event {event} has no frames')
+            height = int(image.shape[0] * THUMBNAIL_WIDTH / image.shape[1])
+            cv2.imwrite(thumbnail, cv2.resize(image, (THUMBNAIL_WIDTH, height), interpolation=cv2.INTER_AREA))
+        return thumbnail
+
+    def delete(self, event):
+        event_path = find_event(self.capture_paths, event)
+        for capture in self.captures:
+            active = capture.active_event
+            if (active != None and active['path'] == event_path) or event_path in capture.post_process_queue:
+                raise PermissionError(f'Event {event} is still being captured')
+            if event_path in capture.post_processing:
+                raise PermissionError(f'Event {event} is still being post-processed')
+        if self.keep_deleted:
+            deleted_path = os.path.join(os.path.dirname(event_path), DELETED_DIR)
+            os.makedirs(deleted_path, exist_ok=True)
+            Capture.update_metadata(event_path, {'deleted': time.time()})
+            shutil.move(event_path, os.path.join(deleted_path, os.path.basename(event_path)))
+            self.logger.info(f'Moved deleted event {event} to {deleted_path}')
+        else:
+            shutil.rmtree(event_path)
+            self.logger.info(f'Deleted event {event}')
+
+    def purge_deleted(self):
+        cutoff = time.time() - self.keep_deleted * 24 * 60 * 60
+        for path in self.capture_paths:
+            if path == None or not os.path.isdir(os.path.join(path, DELETED_DIR)):
+                continue
+            for event in os.listdir(os.path.join(path, DELETED_DIR)):
+                event_path = os.path.join(path, DELETED_DIR, event)
+                if Capture.load_metadata(event_path).get('deleted', 0) < cutoff:
+                    try:
+                        shutil.rmtree(event_path)
+                        self.logger.debug(f'Purged deleted event {event}')
+                    except OSError as e:
+                        self.logger.error(e)
+
+    def purge_loop(self):
+        while True:
+            self.purge_deleted()
+            time.sleep(PURGE_INTERVAL)
@@ -0,0 +1,49 @@
+import cv2
+import numpy as np
+import os
+from dooranalyzer import Analyzer
+
+class ReplayCamera():
+
+    def __init__(self, resolution:tuple, undistort_K:np.array, undistort_D:np.array):
+        self.resolution = resolution
+        self.undistort_K = undistort_K
+        self.undistort_D = undistort_D
+
+def replay_frames(path):
+    imgpath = os.path.join(path, 'images')
+    if os.path.isdir(imgpath):
+        path = imgpath
+    for filename in sorted(os.listdir(path)):
+        if filename[-4:].lower() == '.jpg':
+            yield filename, np.fromfile(os.path.join(path, filename), dtype=np.uint8)
+
+def replay(config, path):
+    frames = replay_frames(path)
+    filename, jpg = next(frames, (None, None))
+    if jpg is None:
+        raise FileNotFoundError(f'No JPEG frames found in {path}')
+    image = cv2.imdecode(jpg, cv2.IMREAD_GRAYSCALE)
+    camera = ReplayCamera((image.shape[1], image.shape[0]), config['camera']['K'], config['camera']['D'])
+    analyzer = Analyzer(
+        camera,
+        config['analyzer']['max_fps'],
+        config['analyzer']['delta_threshold'],
+        config['analyzer']['contour_minimum_area'],
+        config['analyzer']['undistort'],
+        config['analyzer']['undistort_balance'],
+        config['analyzer']['cooldown'],
+        config['analyzer']['motion_end_frames'],
+        0,
+        config['analyzer']['learning_rate'],
+        config['analyzer']['reject_lighting_changes'],
+        config['analyzer']['lighting_change_threshold'],
+        config['analyzer']['blur_size'],
+        config['analyzer']['erode_iterations'],
+        config['analyzer']['dilate_iterations'],
+        start=False
+    )
+    while filename != None:
+        boxes, largest = analyzer.detect(jpg)
+        yield filename, largest, boxes
+        filename, jpg = next(frames, (None, None))
@@ -1,152 +1,417 @@
-import cv2
-import numpy as np
-from doorcam import *
-from evdev import InputDevice
-from select import select
-from logging import getLogger
-
-SCREEN_DECODE_FLAGS = cv2.IMREAD_REDUCED_COLOR_4
-#DECODE_FLAGS = cv2.IMREAD_COLOR
-
-class Screen():
-
-    logger = getLogger('doorcam.screen')
-
-    def __init__(self, camera:Camera, resolution:tuple, rotation, fbdev:str, bldev:str, touchdev:str, color_conv, dtype, activation_period:int, undistort:bool, undistort_balance:float):
-        self.logger.debug(f'Initializing screen located at {fbdev} ...')
-        self.camera = camera
-        self.resolution = resolution
-        self.rotation = rotation
-        self.fbdev = fbdev
-        self.bldev = bldev
-        self.touchdev = touchdev
-        self.dtype = dtype
-        self.color_conv = color_conv
-        self.activation_period = activation_period
-        self.touch_thread = Thread(target=self.touch_loop, daemon=True)
-        self.touch_thread.start()
-        self.fps = 0
-        self.fps_stop = False
-        self.frame = None
-        self.frame_count = 0
-        self.activate = True
-        self.frame_update = False
-        self.camera.add_callback(self.trigger_frame_update)
-        self.setup_undistort(undistort, undistort_balance)
-        self.turn_off()
-        self.fps_thread = Thread(target=self.fps_loop)
-        self.fps_thread.start()
-        self.play_thread = Thread(target=self.play_loop)
-        self.play_thread.start()
-        self.logger.debug(f'Screen located at {fbdev} initialized!')
-    
-    def setup_undistort(self, undistort=True, undistort_balance=1):
-        self.logger.debug(f'Calculating distortion maps...')
-        self.undistort = undistort
-        undistort_DIM=tuple([int(x/4) for x in self.camera.resolution])
-        if type(self.camera.undistort_K) is np.ndarray:
-            undistort_K = self.camera.undistort_K/4
-            undistort_K[2][2] = 1.0
-        else:
-            undistort_K=np.array([[undistort_DIM[1]/2, 0, undistort_DIM[0]/2], [0, undistort_DIM[1]/2, undistort_DIM[1]/2], [0, 0, 1]])
-        if type(self.camera.undistort_D) is np.ndarray:
-            undistort_D = self.camera.undistort_D
-        else:
-            undistort_D = np.array([-0.01, 0.01, -0.01, 0.01])
-        undistort_NK = cv2.fisheye.estimateNewCameraMatrixForUndistortRectify(undistort_K, undistort_D, undistort_DIM, np.eye(3), balance=undistort_balance)
-        self.undistort_map1, self.undistort_map2 = cv2.fisheye.initUndistortRectifyMap(undistort_K, undistort_D, np.eye(3), undistort_NK, undistort_DIM, cv2.CV_16SC2)
-        self.logger.debug(f'Distortion maps calculated!')
-
-    def trigger_frame_update(self, image):
-        self.frame_update = True
-
-    def fb_blank(self, data = 0):
-        blank = np.array([[data]], dtype=self.dtype)
-        blank = np.repeat(blank, self.resolution[0], 1)
-        blank = np.repeat(blank, self.resolution[1], 0)
-        self.fb_write(blank.tobytes())
-        self.logger.debug(f'Screen blanked')
-
-    def fb_write(self, data):
-        with open(self.fbdev, 'wb') as fb:
-            fb.write(data)
-
-    def fb_write_image(self, image):
-        try:
-            self.frame = self.process_image(image)
-            self.fb_write(self.frame.tobytes())
-        except Exception as e:
-            self.logger.error(e)
-
-    def bl_set(self, flag: bool):
-        if flag:
-            out = b'0'
-        else:
-            out = b'1'
-        with open(self.bldev, 'wb') as backlight:
-            backlight.write(out)
-    
-    def play_camera(self):
-        self.activate = True
-        self.logger.debug(f'Screen activated')
-    
-    def touch_loop(self):
-        dev = InputDevice(self.touchdev)
-        while True:
-            r,w,x = select([dev] ,[], [])
-            for event in dev.read():
-                e = event
-            self.logger.debug('Screen touched')
-            self.play_camera()
-            time.sleep(0.1)
-    
-    def fps_loop(self):
-        checkpoint = time.time()
-        while True:
-            self.fps = self.frame_count
-            self.frame_count = 0
-            now = time.time()
-            while now - checkpoint < 1:
-                time.sleep(0.1)
-                now = time.time()
-            checkpoint = now
-    
-    def play_loop(self):
-        while True:
-            while not self.activate:
-                time.sleep(0.1)
-            self.activate = False
-            now = time.time()
-            start = now
-            self.turn_on()
-            while now - start < self.activation_period:
-                self.fb_write_image(self.camera.current_jpg)
-                self.frame_count += 1
-                while not self.frame_update:
-                    time.sleep(0.01)
-                self.frame_update = False
-                now = time.time()
-                if self.activate:
-                    self.activate = False
-                    start = now
-            self.turn_off()
-
-    def process_image(self, src):
-        image = cv2.imdecode(src, SCREEN_DECODE_FLAGS)
-        if self.undistort:
-            image = cv2.remap(image, self.undistort_map1, self.undistort_map2, interpolation=cv2.INTER_LINEAR, borderMode=cv2.BORDER_CONSTANT)
-        if self.rotation != None:
-            image = cv2.rotate(image, self.rotation)
-        image = cv2.resize(image, self.resolution)
-        image = cv2.cvtColor(image, self.color_conv)
-        return image
-
-    def turn_off(self):
-        self.fb_blank()
-        self.bl_set(False)
-        self.logger.debug('Turned off')
-    
-    def turn_on(self):
-        self.fb_blank()
-        self.bl_set(True)
+import cv2
+import numpy as np
+from doorcam import *
+from evdev import InputDevice, ecodes
+from select import select
+from collections import deque
+from threading import Condition
+import datetime
+import shutil
+import os
+from logging import getLogger
+
+SCREEN_DECODE_FLAGS = cv2.IMREAD_REDUCED_COLOR_4
+#DECODE_FLAGS = cv2.IMREAD_COLOR
+IDLE_REFRESH_INTERVAL = 60
+IDLE_TEXT_COLOR = (96, 96, 96)
+NOTIFICATION_MAX = 3
+NOTIFICATION_HEIGHT = 24
+TOUCH_X_CODES = (ecodes.ABS_X, ecodes.ABS_MT_POSITION_X)
+TOUCH_Y_CODES = (ecodes.ABS_Y, ecodes.ABS_MT_POSITION_Y)
+FULL_RATE_BACKLOG = 10
+FADE_STEPS = 20
+
+class Screen():
+
+    logger = getLogger('doorcam.screen')
+
+    def __init__(self, camera:Camera, resolution:tuple, rotation, fbdev:str, bldev:str, touchdev:str, color_conv, dtype, activation_period:int, undistort:bool, undistort_balance:float, swipe_distance:float, long_press_time:float, touch_zones:list, idle_screen:bool, storage_path:str, notification_period:int, frame_policy:str='latest', wake_on:list=('motion', 'touch'), motion_wake_disarmed:bool=True, fade_time:float=0, backlog_drop:str='oldest', backlog_block_time:float=0.1, gesture_callbacks:set=None, zone_callbacks:set=None):
+        self.logger.debug(f'Initializing screen located at {fbdev} ...')
+        self.camera = camera
+        self.resolution = resolution
+        self.rotation = rotation
+        self.fbdev = fbdev
+        self.bldev = bldev
+        self.touchdev = touchdev
+        self.dtype = dtype
+        self.color_conv = color_conv
+        self.activation_period = activation_period
+        self.swipe_distance = swipe_distance
+        self.long_press_time = long_press_time
+        self.gesture_callbacks = gesture_callbacks
+        self.touch_zones = touch_zones
+        self.zone_callbacks = zone_callbacks
+        self.idle_screen = idle_screen
+        self.storage_path = storage_path
+        self.screen_on = False
+        self.last_motion = None
+        self.armed = True
+        self.notification_period = notification_period
+        self.notifications = []
+        self.frame_policy = frame_policy
+        self.wake_on = wake_on
+        self.motion_wake_disarmed = motion_wake_disarmed
+        self.fade_time = fade_time
+        self.fade_generation = 0
+        self.brightness_device = os.path.join(os.path.dirname(self.bldev), 'brightness')
+        self.backlog = deque()
+        self.backlog_drop = backlog_drop
+        self.backlog_block_time = backlog_block_time
+        self.backlog_space = Condition()
+        self.last_id = None
+        self.skipped_count = 0
+        self.rendered_count = 0
+        self.touch_thread = Thread(target=self.touch_loop, daemon=True)
+        self.touch_thread.start()
+        self.fps = 0
+        self.fps_stop = False
+        self.frame = None
+        self.frame_count = 0
+        self.activate = True
+        self.frame_update = False
+        self.camera.add_callback(self.trigger_frame_update)
+        self.setup_undistort(undistort, undistort_balance)
+        self.turn_off()
+        self.fps_thread = Thread(target=self.fps_loop, daemon=True)
+        self.fps_thread.start()
+        self.play_thread = Thread(target=self.play_loop, daemon=True)
+        self.play_thread.start()
+        if self.frame_policy == 'all':
+            self.backlog_thread = Thread(target=self.backlog_loop, daemon=True)
+            self.backlog_thread.start()
+        if self.idle_screen:
+            self.idle_thread = Thread(target=self.idle_loop, daemon=True)
+            self.idle_thread.start()
+        self.logger.debug(f'Screen located at {fbdev} initialized!')
+    
+    def setup_undistort(self, undistort=True, undistort_balance=1):
+        self.logger.debug(f'Calculating distortion maps...')
+        self.undistort = undistort
+        undistort_DIM=tuple([int(x/4) for x in self.camera.resolution])
+        if type(self.camera.undistort_K) is np.ndarray:
+            undistort_K = self.camera.undistort_K/4
+            undistort_K[2][2] = 1.0
+        else:
+            undistort_K=np.array([[undistort_DIM[1]/2, 0, undistort_DIM[0]/2], [0, undistort_DIM[1]/2, undistort_DIM[1]/2], [0, 0, 1]])
+        if type(self.camera.undistort_D) is np.ndarray:
+            undistort_D = self.camera.undistort_D
+        else:
+            undistort_D = np.array([-0.01, 0.01, -0.01, 0.01])
+        undistort_NK = cv2.fisheye.estimateNewCameraMatrixForUndistortRectify(undistort_K, undistort_D, undistort_DIM, np.eye(3), balance=undistort_balance)
+        self.undistort_map1, self.undistort_map2 = cv2.fisheye.initUndistortRectifyMap(undistort_K, undistort_D, np.eye(3), undistort_NK, undistort_DIM, cv2.CV_16SC2)
+        self.logger.debug(f'Distortion maps calculated!')
+
+    def trigger_frame_update(self, image):
+        self.frame_update = True
+
+    def backlog_loop(self):
+        last_id = None
+        while True:
+            while self.camera.frame_id == last_id or not self.screen_on:
+                if not self.screen_on:
+                    last_id = None
+                time.sleep(0.001)
+            frame_id = self.camera.frame_id
+            image = self.camera.current_jpg
+            if last_id != None:
+                self.skipped_count += max(0, frame_id - last_id - 1)
+            last_id = frame_id
+            self.push_backlog(image)
+
+    def push_backlog(self, image):
+        with self.backlog_space:
+            if len(self.backlog) >= FULL_RATE_BACKLOG and self.backlog_drop == 'block':
+                self.backlog_space.wait_for(lambda: len(self.backlog) < FULL_RATE_BACKLOG, self.backlog_block_time)
+            if len(self.backlog) >= FULL_RATE_BACKLOG:
+                self.skipped_count += 1
+                if self.backlog_drop != 'oldest':
+                    return
+                self.backlog.popleft()
+            self.backlog.append(image)
+
+    def next_frame(self):
+        if self.frame_policy == 'all' and len(self.backlog) > 0:
+            with self.backlog_space:
+                image = self.backlog.popleft()
+                self.backlog_space.notify()
+            return image
+        frame_id = self.camera.frame_id
+        if self.last_id != None and self.frame_policy == 'latest':
+            self.skipped_count += max(0, frame_id - self.last_id - 1)
+        self.last_id = frame_id
+        return self.camera.current_jpg
+
+    def fb_blank(self, data = 0):
+        blank = np.array([[data]], dtype=self.dtype)
+        blank = np.repeat(blank, self.resolution[0], 1)
+        blank = np.repeat(blank, self.resolution[1], 0)
+        self.fb_write(blank.tobytes())
+        self.logger.debug(f'Screen blanked')
+
+    def fb_write(self, data):
+        with open(self.fbdev, 'wb') as fb:
+            fb.write(data)
+
+    def fb_write_image(self, image):
+        try:
+            self.frame = self.process_image(image)
+            self.fb_write(self.frame.tobytes())
+        except Exception as e:
+            self.logger.error(e)
+
+    def bl_set(self, flag: bool):
+        if self.fade_time and os.path.isfile(self.brightness_device):
+            self.fade_generation += 1
+            Thread(target=self.bl_fade, args=(flag, self.fade_generation), daemon=True).start()
+            return
+        if flag:
+            self.bl_restore()
+        self.bl_power(flag)
+
+    def bl_power(self, flag: bool):
+        if flag:
+            out = b'0'
+        else:
+            out = b'1'
+        with open(self.bldev, 'wb') as backlight:
+            backlight.write(out)
+
+    def bl_restore(self):
+        if not os.path.isfile(self.brightness_device):
+            return
+        try:
+            with open(os.path.join(os.path.dirname(self.bldev), 'max_brightness'), 'rb') as stream:
+                maximum = int(stream.read())
+            with open(self.brightness_device, 'wb') as brightness:
+                brightness.write(str(maximum).encode())
+        except Exception as e:
+            self.logger.error(f'Could not restore the backlight brightness: {e}')
+
+    def bl_fade(self, flag: bool, generation: int):
+        try:
+            with open(os.path.join(os.path.dirname(self.bldev), 'max_brightness'), 'rb') as stream:
+                maximum = int(stream.read())
+            with open(self.brightness_device, 'rb') as stream:
+                current = int(stream.read())
+            if flag:
+                self.bl_power(True)
+            target = maximum if flag else 0
+            for step in range(1, FADE_STEPS + 1):
+                time.sleep(self.fade_time / FADE_STEPS)
+                if generation != self.fade_generation:
+                    return
+                with open(self.brightness_device, 'wb') as brightness:
+                    brightness.write(str(int(current + (target - current) * step / FADE_STEPS)).encode())
+            if not flag:
+                self.bl_power(False)
+        except Exception as e:
+            self.logger.error(f'Could not fade the backlight, switching it directly: {e}')
+            if flag:
+                self.bl_restore()
+            self.bl_power(flag)
+    
+    def play_camera(self, area=None):
+        if area != None:
+            self.last_motion = time.time()
+            if 'motion' not in self.wake_on or (not self.armed and not self.motion_wake_disarmed):
+                return
+        elif 'touch' not in self.wake_on:
+            return
+        self.activate = True
+        self.logger.debug(f'Screen activated')
+    
+    def touch_loop(self):
+        dev = InputDevice(self.touchdev)
+        axes = (dev.absinfo(ecodes.ABS_X), dev.absinfo(ecodes.ABS_Y))
+        position = [0, 0]
+        touching = False
+        press = None
+        while True:
+            r,w,x = select([dev] ,[], [])
+            for event in dev.read():
+                if event.type == ecodes.EV_ABS and event.code in TOUCH_X_CODES:
+                    position[0] = event.value
+                elif event.type == ecodes.EV_ABS and event.code in TOUCH_Y_CODES:
+                    position[1] = event.value
+                elif event.type == ecodes.EV_KEY and event.code == ecodes.BTN_TOUCH:
+                    touching = event.value != 0
+                elif event.type == ecodes.EV_SYN and event.code == ecodes.SYN_REPORT:
+                    if touching and press == None:
+                        press = (event.timestamp(), self.normalize_touch(position, axes))
+                        self.check_zones(press[1])
+                    elif not touching and press != None:
+                        self.trigger_gesture(self.classify_gesture(press, (event.timestamp(), self.normalize_touch(position, axes))))
+                        press = None
+            self.logger.debug('Screen touched')
+            self.play_camera()
+            time.sleep(0.1)
+
+    def normalize_touch(self, position, axes):
+        x, y = [(position[i] - axes[i].min) / max(1, axes[i].max - axes[i].min) for i in range(2)]
+        if self.rotation == cv2.ROTATE_90_CLOCKWISE:
+            return (y, 1 - x)
+        elif self.rotation == cv2.ROTATE_90_COUNTERCLOCKWISE:
+            return (1 - y, x)
+        elif self.rotation == cv2.ROTATE_180:
+            return (1 - x, 1 - y)
+        else:
+            return (x, y)
+
+    def check_zones(self, point):
+        for zone in self.touch_zones:
+            if zone['x'] <= point[0] < zone['x'] + zone['width'] and zone['y'] <= point[1] < zone['y'] + zone['height']:
+                self.logger.debug(f'Touch zone {zone["name"]} pressed')
+                if self.zone_callbacks != None:
+                    for callback in self.zone_callbacks:
+                        Thread(target=callback, args=(zone['name'], ), daemon=True).start()
+
+    def add_zone_callback(self, callback):
+        if self.zone_callbacks != None:
+            self.zone_callbacks.add(callback)
+        else:
+            self.zone_callbacks = set((callback,))
+
+    def classify_gesture(self, press, release):
+        dx = release[1][0] - press[1][0]
+        if dx >= self.swipe_distance:
+            return 'swipe_right'
+        elif dx <= -self.swipe_distance:
+            return 'swipe_left'
+        elif release[0] - press[0] >= self.long_press_time:
+            return 'long_press'
+        else:
+            return 'tap'
+
+    def trigger_gesture(self, gesture):
+        self.logger.debug(f'Detected {gesture} gesture')
+        if self.gesture_callbacks != None:
+            for callback in self.gesture_callbacks:
+                Thread(target=callback, args=(gesture, ), daemon=True).start()
+
+    def add_gesture_callback(self, callback):
+        if self.gesture_callbacks != None:
+            self.gesture_callbacks.add(callback)
+        else:
+            self.gesture_callbacks = set((callback,))
+    
+    def fps_loop(self):
+        checkpoint = time.time()
+        while True:
+            self.fps = self.frame_count
+            self.frame_count = 0
+            now = time.time()
+            while now - checkpoint < 1:
+                time.sleep(0.1)
+                now = time.time()
+            checkpoint = now
+    
+    def stats(self):
+        return {
+            'fps': self.fps,
+            'rendered': self.rendered_count,
+            'skipped': self.skipped_count,
+            'screen_on': self.screen_on
+        }
+
+    def play_loop(self):
+        while True:
+            while not self.activate:
+                time.sleep(0.1)
+            self.activate = False
+            now = time.time()
+            start = now
+            self.last_id = None
+            with self.backlog_space:
+                self.backlog.clear()
+                self.backlog_space.notify_all()
+            self.turn_on()
+            while now - start < self.activation_period:
+                self.fb_write_image(self.next_frame())
+                self.frame_count += 1
+                self.rendered_count += 1
+                while not self.frame_update and len(self.backlog) == 0:
+                    time.sleep(0.01)
+                self.frame_update = False
+                now = time.time()
+                if self.activate:
+                    self.activate = False
+                    start = now
+            self.turn_off()
+
+    def process_image(self, src):
+        image = cv2.imdecode(src, SCREEN_DECODE_FLAGS)
+        if self.undistort:
+            image = cv2.remap(image, self.undistort_map1, self.undistort_map2, interpolation=cv2.INTER_LINEAR, borderMode=cv2.BORDER_CONSTANT)
+        if len(self.notifications) > 0:
+            image = self.draw_notifications(image)
+        if self.rotation != None:
+            image = cv2.rotate(image, self.rotation)
+        image = cv2.resize(image, self.resolution)
+        image = cv2.cvtColor(image, self.color_conv)
+        return image
+
+    def notify(self, message):
+        self.logger.debug(f'Showing notification: {message}')
+        self.notifications.append((time.time() + self.notification_period, str(message)))
+
+    def draw_notifications(self, image):
+        now = time.time()
+        self.notifications = [x for x in self.notifications if x[0] > now]
+        for i, (expiry, message) in enumerate(self.notifications[:NOTIFICATION_MAX]):
+            top = i * NOTIFICATION_HEIGHT
+            image[top:top + NOTIFICATION_HEIGHT] = image[top:top + NOTIFICATION_HEIGHT] // 3
+            cv2.putText(image, message, (5, top + NOTIFICATION_HEIGHT - 7), cv2.FONT_HERSHEY_SIMPLEX, 0.5, (255, 255, 255), 1)
+        return image
+
+    def idle_loop(self):
+        while True:
+            if not self.screen_on:
+                try:
+                    self.fb_write_idle()
+                except Exception as e:
+                    self.logger.error(e)
+            time.sleep(IDLE_REFRESH_INTERVAL - time.time() % IDLE_REFRESH_INTERVAL)
+
+    def fb_write_idle(self):
+        if self.rotation == cv2.ROTATE_90_CLOCKWISE or self.rotation == cv2.ROTATE_90_COUNTERCLOCKWISE:
+            width, height = self.resolution[1], self.resolution[0]
+        else:
+            width, height = self.resolution
+        image = np.zeros((height, width, 3), dtype=np.uint8)
+        lines = [datetime.datetime.now().strftime('%H:%M'), 'Armed' if self.armed else 'Disarmed']
+        if self.last_motion != None:
+            lines.append(f'Last motion {int((time.time() - self.last_motion) / 60)} min ago')
+        usage = shutil.disk_usage(self.storage_path)
+        lines.append(f'Storage {100 * usage.used / usage.total:.0f}% used')
+        y = height // 3
+        for i, line in enumerate(lines):
+            scale = 2 if i == 0 else 0.8
+            (text_width, text_height), baseline = cv2.getTextSize(line, cv2.FONT_HERSHEY_SIMPLEX, scale, 2)
+            cv2.putText(image, line, ((width - text_width) // 2, y), cv2.FONT_HERSHEY_SIMPLEX, scale, IDLE_TEXT_COLOR, 2)
+            y += text_height + baseline + 20
+        if self.rotation != None:
+            image = cv2.rotate(image, self.rotation)
+        image = cv2.cvtColor(image, self.color_conv)
+        self.fb_write(image.tobytes())
+
+    def set_armed(self, armed:bool):
+        self.armed = armed
+        self.notify('Armed' if armed else 'Disarmed')
+        if self.idle_screen and not self.screen_on:
+            self.fb_write_idle()
+
+    def turn_off(self):
+        self.screen_on = False
+        if self.idle_screen:
+            self.fb_write_idle()
+            self.logger.debug('Showing idle screen')
+        else:
+            self.fb_blank()
+            self.bl_set(False)
+            self.logger.debug('Turned off')
+    
+    def turn_on(self):
+        self.screen_on = True
+        self.fb_blank()
+        self.bl_set(True)
         self.logger.debug('Turned on')
\ No newline at end of file
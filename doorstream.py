@@ -1,54 +1,635 @@
-import cv2
-import numpy as np
-from http.server import HTTPServer, BaseHTTPRequestHandler
-from socketserver import ThreadingMixIn
-from doorcam import *
-from logging import getLogger
-
-class MJPGServer(ThreadingMixIn, HTTPServer):
-    pass
-
-class MJPGHandler(BaseHTTPRequestHandler):
-
-    logger = getLogger('doorcam.stream')
-
-    def __init__(self, camera: Camera, *args, **kwargs):
-        self.camera = camera
-        self.frame_update = False
-        self.camera.add_callback(self.trigger_frame_update)
-        super().__init__(*args, **kwargs)
-
-    def trigger_frame_update(self, image):
-        self.frame_update = True
-
-    def do_GET(self):
-        
-        if self.path == '/stream.mjpg':
-
-            self.send_response(200)
-            self.send_header('Age', 0)
-            self.send_header('Cache-Control', 'no-cache, private')
-            self.send_header('Pragma', 'no-cache')
-            self.send_header('Content-Type', 'multipart/x-mixed-replace; boundary=FRAME')
-            self.end_headers()
-            self.logger.info(f'Serving MJPG stream to {self.client_address}')
-            while True:
-                image = self.camera.current_jpg
-                try:
-                    self.wfile.write(b'--FRAME\r\n')
-                    self.send_header('Content-type', 'image/jpeg')
-                    self.send_header('Content-length', str(image.size))
-                    self.end_headers()
-                    self.wfile.write(image.tostring())
-                    self.wfile.write(b'\r\n')
-                except Exception as e:
-                    self.logger.error(e)
-                    self.logger.info(f'Stopping MJPG stream to {self.client_address}')
-                    self.camera.remove_callback(self.trigger_frame_update)
-                    break
-                while not self.frame_update:
-                    time.sleep(0.01)
-                self.frame_update = False
-        else:
-            self.send_error(404)
-            self.end_headers()
+import cv2
+import numpy as np
+from http.server import HTTPServer, BaseHTTPRequestHandler
+from socketserver import ThreadingMixIn
+from doorcam import *
+from logging import getLogger
+import os
+import subprocess
+import base64
+import hmac
+import json
+import ipaddress
+import ssl
+import hashlib
+import struct
+import queue
+import re
+from select import select
+from doorevents import parse_filter
+from threading import Lock
+from urllib.parse import urlsplit, parse_qs
+
+HLS_PLAYLIST = 'playlist.m3u8'
+HLS_SEGMENT_FORMAT = 'segment_%05d.ts'
+HLS_FILE_PATTERN = re.compile(r'(segment_\d+\.ts|playlist\.m3u8(\.tmp)?)')
+HLS_CONTENT_TYPES = {
+    '.m3u8': 'application/vnd.apple.mpegurl',
+    '.ts': 'video/mp2t'
+}
+SCALE_CACHE_FRAMES = 5
+FILE_CHUNK_SIZE = 64 * 1024
+WEBSOCKET_GUID = '258EAFA5-E914-47DA-95CA-C5AB0DC85B11'
+WEBSOCKET_QUEUE_SIZE = 100
+WEBSOCKET_POLL_INTERVAL = 0.5
+WEBSOCKET_MAX_FRAME = 4096
+SEND_LATENCY_SMOOTHING = 0.2
+VIDEO_CONTENT_TYPES = {
+    '.mp4': 'video/mp4',
+    '.mkv': 'video/x-matroska',
+    '.webm': 'video/webm'
+}
+
+class MJPGServer(ThreadingMixIn, HTTPServer):
+
+    def __init__(self, server_address, handler, allowed_networks:list=None, tls_cert=None, tls_key=None):
+        self.allowed_networks = allowed_networks
+        super().__init__(server_address, handler)
+        if tls_cert != None:
+            context = ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER)
+            context.load_cert_chain(tls_cert, tls_key)
+            self.socket = context.wrap_socket(self.socket, server_side=True, do_handshake_on_connect=False)
+
+    def allows(self, address):
+        if not self.allowed_networks:
+            return True
+        address = ipaddress.ip_address(address)
+        if address.version == 6 and address.ipv4_mapped != None:
+            address = address.ipv4_mapped
+        return any(address in network for network in self.allowed_networks)
+
+class MJPGHandler(BaseHTTPRequestHandler):
+
+    logger = getLogger('doorcam.stream')
+
+    def __init__(self, camera: Camera, hls_path, auth, rotation, stats, quality, scaler, arm, library, restarts, events, frame_headers, *args, **kwargs):
+        self.camera = camera
+        self.frame_headers = frame_headers
+        self.events = events
+        self.arm = arm
+        self.library = library
+        self.restarts = restarts
+        self.scaler = scaler
+        self.hls_path = hls_path
+        self.auth = auth
+        self.rotation = rotation
+        self.stats = stats
+        self.quality = quality
+        self.frame_update = False
+        super().__init__(*args, **kwargs)
+
+    def trigger_frame_update(self, image):
+        self.frame_update = True
+
+    def authorize(self):
+        if not self.server.allows(self.client_address[0]):
+            self.logger.debug(f'Rejected request from {self.client_address}, not in allowed_networks')
+            self.send_error(403)
+            return False
+        if self.auth != None and not self.auth.check(self.headers.get('Authorization')):
+            self.logger.info(f'Rejected unauthenticated request from {self.client_address}')
+            self.send_response(401)
+            self.send_header('WWW-Authenticate', self.auth.challenge())
+            self.send_header('Content-Length', '0')
+            self.end_headers()
+            return False
+        return True
+
+    def do_GET(self):
+
+        if not self.authorize():
+            return
+
+        url = urlsplit(self.path)
+        resolution = parse_qs(url.query).get('resolution', [None])[0]
+        if resolution != None and (self.scaler == None or resolution not in self.scaler.resolutions):
+            self.send_error(400, f'Unsupported resolution {resolution}')
+            return
+
+        if url.path == '/stream.mjpg' and self.hls_path == None:
+            if not self.stats.connect(self.client_address):
+                self.logger.info(f'Rejected MJPG stream to {self.client_address}, {self.stats.clients} clients already connected')
+                self.send_error(503, 'Too Many Connections')
+                return
+            try:
+                self.send_stream(resolution)
+            finally:
+                self.stats.disconnect(self.client_address)
+        elif url.path == '/snapshot.jpg':
+            self.send_snapshot(resolution)
+        elif url.path == '/armed' and self.arm != None:
+            self.send_json({'armed': self.arm.armed, 'rearm_time': self.arm.rearm_time})
+        elif url.path == '/feed' and self.events != None:
+            self.send_event_feed(url)
+        elif url.path.startswith('/events') and self.library != None:
+            self.send_event(url)
+        elif self.hls_path != None and os.path.splitext(url.path)[1] in HLS_CONTENT_TYPES:
+            self.send_hls_file(os.path.basename(url.path))
+        else:
+            self.send_error(404)
+            self.end_headers()
+
+    def do_POST(self):
+
+        if not self.authorize():
+            return
+
+        url = urlsplit(self.path)
+        if url.path in ('/arm', '/disarm') and self.arm != None:
+            rearm = parse_qs(url.query).get('rearm', [None])[0]
+            try:
+                rearm = float(rearm) if rearm != None else None
+            except ValueError:
+                self.send_error(400, f'Invalid rearm time {rearm}')
+                return
+            self.logger.info(f'{url.path[1:].capitalize()} requested by {self.client_address}')
+            self.arm.set_armed(url.path == '/arm', rearm)
+            self.send_json({'armed': self.arm.armed, 'rearm_time': self.arm.rearm_time})
+        elif url.path.startswith('/restart/') and url.path[len('/restart/'):] in self.restarts:
+            component = url.path[len('/restart/'):]
+            if not self.restarts[component]():
+                self.send_error(409, f'{component} is already restarting')
+                return
+            self.logger.info(f'Restart of {component} requested by {self.client_address}')
+            self.send_json({'restarted': component})
+        else:
+            self.send_error(404)
+            self.end_headers()
+
+    def do_DELETE(self):
+
+        if not self.authorize():
+            return
+
+        parts = urlsplit(self.path).path.strip('/').split('/')
+        if len(parts) == 2 and parts[0] == 'events' and self.library != None:
+            try:
+                self.library.delete(parts[1])
+            except FileNotFoundError:
+                self.send_error(404)
+                return
+            except PermissionError as e:
+                self.send_error(409, str(e))
+                return
+            self.logger.info(f'Event {parts[1]} deleted by {self.client_address}')
+            self.send_json({'deleted': parts[1]})
+        else:
+            self.send_error(404)
+            self.end_headers()
+
+    def send_event(self, url):
+        parts = url.path.strip('/').split('/')
+        try:
+            if len(parts) == 1:
+                query = parse_qs(url.query)
+                self.send_json(self.library.list(int(query.get('offset', [0])[0]), int(query.get('limit', [50])[0])))
+            elif len(parts) == 2 and parts[1] == 'usage':
+                self.send_json(self.library.usage())
+            elif len(parts) == 2:
+                self.send_json(self.library.get(parts[1]))
+            elif len(parts) == 3 and parts[2] == 'video':
+                video = self.library.video_path(parts[1])
+                self.send_file(video, VIDEO_CONTENT_TYPES.get(os.path.splitext(video)[1], 'application/octet-stream'))
+            elif len(parts) == 3 and parts[2] == 'thumbnail':
+                self.send_file(self.library.thumbnail_path(parts[1]), 'image/jpeg')
+            else:
+                self.send_error(404)
+        except FileNotFoundError:
+            self.send_error(404)
+        except ValueError:
+            self.send_error(400)
+
+    def send_file(self, path, content_type):
+        size = os.path.getsize(path)
+        byte_range = parse_range(self.headers.get('Range'), size)
+        if byte_range == False:
+            self.send_response(416)
+            self.send_header('Content-Range', f'bytes */{size}')
+            self.send_header('Content-Length', '0')
+            self.end_headers()
+            return
+        with open(path, 'rb') as stream:
+            if byte_range == None:
+                start, end = 0, size - 1
+                self.send_response(200)
+            else:
+                start, end = byte_range
+                self.send_response(206)
+                self.send_header('Content-Range', f'bytes {start}-{end}/{size}')
+            self.send_header('Content-Type', content_type)
+            self.send_header('Accept-Ranges', 'bytes')
+            self.send_header('Content-Length', str(end - start + 1))
+            self.end_headers()
+            stream.seek(start)
+            remaining = end - start + 1
+            while remaining > 0:
+                data = stream.read(min(FILE_CHUNK_SIZE, remaining))
+                if not data:
+                    break
+                self.wfile.write(data)
+                remaining -= len(data)
+
+    def send_event_feed(self, url):
+        key = self.headers.get('Sec-WebSocket-Key')
+        if key == None or self.headers.get('Upgrade', '').lower() != 'websocket':
+            self.send_error(400, 'Expected a WebSocket upgrade')
+            return
+        query = parse_qs(url.query)
+        raw_filter = {}
+        if 'types' in query:
+            raw_filter['types'] = query['types'][0].split(',')
+        if 'start' in query or 'end' in query:
+            raw_filter['start'] = query.get('start', [None])[0]
+            raw_filter['end'] = query.get('end', [None])[0]
+        try:
+            event_filter = parse_filter(raw_filter) if len(raw_filter) > 0 else None
+        except Exception as e:
+            self.send_error(400, str(e))
+            return
+        self.protocol_version = 'HTTP/1.1'
+        self.send_response(101)
+        self.send_header('Upgrade', 'websocket')
+        self.send_header('Connection', 'Upgrade')
+        self.send_header('Sec-WebSocket-Accept', base64.b64encode(hashlib.sha1((key + WEBSOCKET_GUID).encode()).digest()).decode())
+        self.end_headers()
+        self.close_connection = True
+        pending = queue.Queue(WEBSOCKET_QUEUE_SIZE)
+        overflowed = []
+        def deliver(data):
+            try:
+                pending.put_nowait(data)
+            except queue.Full:
+                overflowed.append(True)
+        self.events.subscribe(deliver, event_filter)
+        self.logger.info(f'Event feed client {self.client_address} connected')
+        try:
+            while len(overflowed) == 0:
+                if len(select([self.connection], [], [], 0)[0]) > 0 and not self.read_websocket_frame():
+                    break
+                try:
+                    data = pending.get(timeout=WEBSOCKET_POLL_INTERVAL)
+                except queue.Empty:
+                    continue
+                self.write_websocket_frame(0x1, data.rstrip(b'\n'))
+            if len(overflowed) > 0:
+                self.logger.info(f'Closing event feed to {self.client_address}, it fell behind')
+                self.write_websocket_frame(0x8, struct.pack('!H', 1008) + b'Too slow')
+        except OSError as e:
+            self.logger.debug(f'Event feed client {self.client_address} dropped: {e}')
+        finally:
+            self.events.unsubscribe(deliver)
+        self.logger.info(f'Event feed client {self.client_address} disconnected')
+
+    def read_websocket_frame(self):
+        header = self.rfile.read(2)
+        if len(header) < 2:
+            return False
+        opcode = header[0] & 0x0f
+        length = header[1] & 0x7f
+        if length == 126:
+            length = struct.unpack('!H', self.rfile.read(2))[0]
+        elif length == 127:
+            length = struct.unpack('!Q', self.rfile.read(8))[0]
+        if length > WEBSOCKET_MAX_FRAME:
+            return False
+        mask = self.rfile.read(4) if header[1] & 0x80 else None
+        payload = self.rfile.read(length)
+        if mask != None:
+            payload = bytes(x ^ mask[i % 4] for i, x in enumerate(payload))
+        if opcode == 0x8:
+            self.write_websocket_frame(0x8, payload[:2])
+            return False
+        if opcode == 0x9:
+            self.write_websocket_frame(0xA, payload)
+        return True
+
+    def write_websocket_frame(self, opcode, payload:bytes):
+        header = bytes((0x80 | opcode, ))
+        if len(payload) < 126:
+            header += bytes((len(payload), ))
+        elif len(payload) < 65536:
+            header += bytes((126, )) + struct.pack('!H', len(payload))
+        else:
+            header += bytes((127, )) + struct.pack('!Q', len(payload))
+        self.wfile.write(header + payload)
+
+    def send_json(self, values:dict):
+        data = json.dumps(values, default=str).encode()
+        self.send_response(200)
+        self.send_header('Cache-Control', 'no-cache, private')
+        self.send_header('Content-Type', 'application/json')
+        self.send_header('Content-Length', str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def get_image(self, resolution):
+        if resolution == None:
+            return self.camera.current_jpg
+        return self.scaler.get(resolution)
+
+    def send_stream(self, resolution=None):
+        self.send_response(200)
+        self.send_header('Age', 0)
+        self.send_header('Cache-Control', 'no-cache, private')
+        self.send_header('Pragma', 'no-cache')
+        self.send_header('Content-Type', 'multipart/x-mixed-replace; boundary=FRAME')
+        self.end_headers()
+        self.logger.info(f'Serving MJPG stream to {self.client_address}')
+        self.camera.add_callback(self.trigger_frame_update)
+        frame_count = 0
+        checkpoint = time.time()
+        quality = self.quality(self.client_address) if self.quality != None else None
+        latency = 0
+        while True:
+            try:
+                frame_id, frame_time = self.camera.frame_id, self.camera.frame_time
+                image = self.get_image(resolution)
+                start = time.time()
+                if quality != None:
+                    image = quality.adapt(image)
+                self.wfile.write(b'--FRAME\r\n')
+                self.send_header('Content-type', 'image/jpeg')
+                self.send_header('Content-length', str(image.size))
+                if self.frame_headers:
+                    self.send_frame_headers(frame_id, frame_time)
+                self.end_headers()
+                self.wfile.write(image.tostring())
+                self.wfile.write(b'\r\n')
+                if quality != None:
+                    quality.measure(time.time() - start)
+                latency += (time.time() - start - latency) * SEND_LATENCY_SMOOTHING
+            except Exception as e:
+                self.logger.error(e)
+                self.logger.info(f'Stopping MJPG stream to {self.client_address}')
+                self.camera.remove_callback(self.trigger_frame_update)
+                break
+            frame_count += 1
+            now = time.time()
+            if now - checkpoint >= 1:
+                self.stats.client_fps[self.client_address] = frame_count
+                frame_count = 0
+                checkpoint = now
+            behind = int(latency * self.camera.max_fps)
+            if behind > 0:
+                self.stats.skip(self.client_address, behind)
+            for i in range(behind + 1):
+                while not self.frame_update:
+                    time.sleep(0.01)
+                self.frame_update = False
+
+    def send_snapshot(self, resolution=None):
+        if self.camera.current_jpg is None:
+            self.send_error(503, 'No frame available')
+            return
+        try:
+            frame_id, frame_time = self.camera.frame_id, self.camera.frame_time
+            image = self.get_image(resolution)
+            if self.rotation != None:
+                ret, image = cv2.imencode('.jpg', cv2.rotate(cv2.imdecode(image, cv2.IMREAD_COLOR), self.rotation))
+            data = image.tobytes()
+        except Exception as e:
+            self.logger.error(e)
+            self.send_error(500)
+            return
+        self.send_response(200)
+        self.send_header('Cache-Control', 'no-cache, private')
+        self.send_header('Content-Type', 'image/jpeg')
+        self.send_header('Content-Length', str(len(data)))
+        if self.frame_headers:
+            self.send_frame_headers(frame_id, frame_time)
+        self.end_headers()
+        self.wfile.write(data)
+
+    def send_frame_headers(self, frame_id, frame_time):
+        self.send_header('X-Frame-Id', str(frame_id))
+        if frame_time != None:
+            self.send_header('X-Timestamp', f'{frame_time:.6f}')
+
+    def send_hls_file(self, filename):
+        fullpath = os.path.join(self.hls_path, filename)
+        try:
+            with open(fullpath, 'rb') as hls_file:
+                data = hls_file.read()
+        except OSError:
+            self.send_error(404)
+            self.end_headers()
+            return
+        self.send_response(200)
+        self.send_header('Cache-Control', 'no-cache, private')
+        self.send_header('Content-Type', HLS_CONTENT_TYPES[os.path.splitext(filename)[1]])
+        self.send_header('Content-Length', str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+def parse_range(header, size):
+    if header == None or not header.startswith('bytes=') or ',' in header:
+        return None
+    start, _, end = header[len('bytes='):].strip().partition('-')
+    try:
+        if start == '':
+            suffix = int(end)
+            if suffix < 0:
+                return None
+            start = max(0, size - suffix)
+            end = size - 1
+        else:
+            start = int(start)
+            end = int(end) if end != '' else max(start, size - 1)
+            if start < 0 or start > end:
+                return None
+    except ValueError:
+        return None
+    if start >= size:
+        return False
+    return start, min(end, size - 1)
+
+class StreamStats():
+
+    def __init__(self, max_clients=None):
+        self.max_clients = max_clients
+        self.clients = 0
+        self.peak_clients = 0
+        self.client_fps = {}
+        self.client_skipped = {}
+        self.lock = Lock()
+
+    def connect(self, address):
+        with self.lock:
+            if self.max_clients and self.clients >= self.max_clients:
+                return False
+            self.clients += 1
+            self.peak_clients = max(self.peak_clients, self.clients)
+            self.client_fps[address] = 0
+            self.client_skipped[address] = 0
+            return True
+
+    def disconnect(self, address):
+        with self.lock:
+            self.clients -= 1
+            self.client_fps.pop(address, None)
+            self.client_skipped.pop(address, None)
+
+    def skip(self, address, frames):
+        with self.lock:
+            if address in self.client_skipped:
+                self.client_skipped[address] += frames
+
+class FrameScaler():
+
+    logger = getLogger('doorcam.stream.scaler')
+
+    def __init__(self, camera: Camera, resolutions:dict):
+        self.camera = camera
+        self.resolutions = resolutions
+        self.decoded = (None, None)
+        self.cache = {}
+        self.lock = Lock()
+
+    def get(self, resolution):
+        with self.lock:
+            frame_id = self.camera.frame_id
+            image = self.camera.current_jpg
+            key = (frame_id, resolution)
+            if key not in self.cache:
+                if self.decoded[0] != frame_id:
+                    self.decoded = (frame_id, cv2.imdecode(image, cv2.IMREAD_COLOR))
+                frame = cv2.resize(self.decoded[1], self.resolutions[resolution], interpolation=cv2.INTER_AREA)
+                ret, self.cache[key] = cv2.imencode('.jpg', frame)
+                for cached in [x for x in self.cache if x[0] <= frame_id - SCALE_CACHE_FRAMES]:
+                    del self.cache[cached]
+            return self.cache[key]
+
+class QualityAdapter():
+
+    logger = getLogger('doorcam.stream.quality')
+
+    def __init__(self, threshold:float, reduced_quality:int, client=None, smoothing:float=0.1):
+        self.threshold = threshold
+        self.client = client
+        self.reduced_quality = reduced_quality
+        self.smoothing = smoothing
+        self.average = 0
+        self.target_quality = None
+
+    def set_target_quality(self, quality):
+        if quality != self.target_quality:
+            self.target_quality = quality
+            if quality == None:
+                self.logger.info(f'Restoring full stream quality for {self.client}')
+            else:
+                self.logger.info(f'Reducing stream quality to {quality} for {self.client}')
+
+    def measure(self, duration:float):
+        self.average += (duration - self.average) * self.smoothing
+        if self.target_quality == None and self.average > self.threshold:
+            self.set_target_quality(self.reduced_quality)
+        elif self.target_quality != None and self.average < self.threshold / 2:
+            self.set_target_quality(None)
+
+    def adapt(self, image):
+        quality = self.target_quality
+        if quality == None:
+            return image
+        frame = cv2.imdecode(image, cv2.IMREAD_COLOR)
+        ret, image = cv2.imencode('.jpg', frame, (cv2.IMWRITE_JPEG_QUALITY, quality))
+        return image
+
+class StreamAuth():
+
+    def __init__(self, username=None, password=None, token=None):
+        self.basic = None
+        self.token = None
+        if username != None and password != None:
+            self.basic = base64.b64encode(f'{username}:{password}'.encode())
+        if token != None:
+            self.token = str(token).encode()
+
+    def check(self, header):
+        if header == None:
+            return False
+        scheme, _, credentials = header.partition(' ')
+        credentials = credentials.strip().encode()
+        if scheme.lower() == 'basic' and self.basic != None:
+            return hmac.compare_digest(credentials, self.basic)
+        if scheme.lower() == 'bearer' and self.token != None:
+            return hmac.compare_digest(credentials, self.token)
+        return False
+
+    def challenge(self):
+        if self.basic != None:
+            return 'Basic realm="doorcam"'
+        else:
+            return 'Bearer realm="doorcam"'
+
+class HLSEncoder():
+
+    logger = getLogger('doorcam.stream.hls')
+
+    def __init__(self, camera: Camera, path, segment_time:int, window:int):
+        self.logger.debug(f'Initializing HLS encoder at {path}')
+        self.camera = camera
+        self.path = os.path.abspath(path)
+        self.segment_time = segment_time
+        self.window = window
+        self.clear()
+        self.process = None
+        self.frame_update = False
+        self.camera.add_callback(self.trigger_frame_update)
+        self.encode_thread = Thread(target=self.encode_loop, daemon=True)
+        self.encode_thread.start()
+        self.logger.debug(f'HLS encoder at {path} initialized!')
+
+    def trigger_frame_update(self, image):
+        self.frame_update = True
+
+    def clear(self):
+        if not os.path.isdir(self.path):
+            os.makedirs(self.path)
+            return
+        for filename in os.listdir(self.path):
+            if HLS_FILE_PATTERN.fullmatch(filename):
+                os.remove(os.path.join(self.path, filename))
+            else:
+                self.logger.debug(f'Leaving {filename} in {self.path}, it is not an HLS file')
+
+    def open(self):
+        list_size = max(1, int(self.window / self.segment_time))
+        command = [
+            'ffmpeg', '-loglevel', 'error',
+            '-f', 'mjpeg', '-framerate', str(self.camera.max_fps), '-i', '-',
+            '-c:v', 'libx264', '-preset', 'ultrafast', '-tune', 'zerolatency', '-pix_fmt', 'yuv420p',
+            '-g', str(self.camera.max_fps * self.segment_time),
+            '-f', 'hls',
+            '-hls_time', str(self.segment_time),
+            '-hls_list_size', str(list_size),
+            '-hls_flags', 'delete_segments+omit_endlist',
+            '-hls_segment_filename', os.path.join(self.path, HLS_SEGMENT_FORMAT),
+            os.path.join(self.path, HLS_PLAYLIST)
+        ]
+        self.process = subprocess.Popen(command, stdin=subprocess.PIPE)
+        self.logger.info(f'Writing HLS segments to {self.path}')
+
+    def close(self):
+        if self.process != None:
+            try:
+                self.process.stdin.close()
+                self.process.wait(5)
+            except Exception as e:
+                self.logger.error(e)
+                self.process.kill()
+            self.process = None
+
+    def encode_loop(self):
+        while True:
+            try:
+                if self.process == None or self.process.poll() != None:
+                    self.close()
+                    self.open()
+                while not self.frame_update:
+                    time.sleep(0.01)
+                self.frame_update = False
+                self.process.stdin.write(self.camera.current_jpg.tobytes())
+            except Exception as e:
+                self.logger.error(e)
+                self.close()
+                time.sleep(1)
@@ -0,0 +1,97 @@
+import cv2
+import os
+import datetime
+from logging import getLogger
+from doorcapture import TIME_FORMAT, VIDEO_CODECS, Capture, list_events, event_time
+
+TIMELAPSE_FOURCC = 'mp4v'
+
+logger = getLogger('doorcam.timelapse')
+
+def events_in_range(capture_paths, start:datetime.datetime, end:datetime.datetime):
+    return [x for x in list_events(capture_paths) if start <= x[0] < end]
+
+def event_video(event_path):
+    video_file = Capture.load_metadata(event_path).get('video')
+    if video_file == None:
+        video_file = next((f'{os.path.basename(event_path)}.{x}' for x in VIDEO_CODECS if os.path.isfile(os.path.join(event_path, f'{os.path.basename(event_path)}.{x}'))), '')
+    return os.path.join(event_path, video_file)
+
+def event_images(imgpath):
+    return sorted(x for x in os.listdir(imgpath) if x[-4:].lower() == '.jpg')
+
+def event_frames(event_path):
+    imgpath = os.path.join(event_path, 'images')
+    video_file = event_video(event_path)
+    if os.path.isdir(imgpath):
+        for filename in event_images(imgpath):
+            image = cv2.imread(os.path.join(imgpath, filename), flags=cv2.IMREAD_COLOR)
+            if image is not None:
+                yield datetime.datetime.strptime(filename[:-4], TIME_FORMAT), image
+    elif os.path.isfile(video_file):
+        start = event_time(event_path)
+        video = cv2.VideoCapture(video_file)
+        fps = video.get(cv2.CAP_PROP_FPS) or 1
+        index = 0
+        try:
+            while True:
+                ret, image = video.read()
+                if not ret:
+                    break
+                yield start + datetime.timedelta(seconds=index / fps), image
+                index += 1
+        finally:
+            video.release()
+
+def representative_frame(event_path):
+    imgpath = os.path.join(event_path, 'images')
+    video_file = event_video(event_path)
+    if os.path.isdir(imgpath):
+        images = event_images(imgpath)
+        if len(images) == 0:
+            return None
+        return cv2.imread(os.path.join(imgpath, images[len(images) // 2]), flags=cv2.IMREAD_COLOR)
+    elif os.path.isfile(video_file):
+        video = cv2.VideoCapture(video_file)
+        try:
+            count = int(video.get(cv2.CAP_PROP_FRAME_COUNT))
+            if count > 0:
+                video.set(cv2.CAP_PROP_POS_FRAMES, count // 2)
+            ret, image = video.read()
+            return image if ret else None
+        finally:
+            video.release()
+    return None
+
+def sampled_frames(events, interval:float):
+    next_sample = None
+    for timestamp, event_path in events:
+        for frame_time, image in event_frames(event_path):
+            if next_sample == None or frame_time >= next_sample:
+                next_sample = frame_time + datetime.timedelta(seconds=interval)
+                yield image
+
+def generate_timelapse(capture_paths, start:datetime.datetime, end:datetime.datetime, dest, fps:int, interval:float=None):
+    events = events_in_range(capture_paths, start, end)
+    logger.info(f'Generating timelapse of {len(events)} events between {start} and {end}')
+    if interval == None:
+        frames = (representative_frame(event_path) for timestamp, event_path in events)
+    else:
+        frames = sampled_frames(events, interval)
+    video_writer = None
+    count = 0
+    try:
+        for image in frames:
+            if image is None:
+                continue
+            if video_writer == None:
+                resolution = (image.shape[1], image.shape[0])
+                video_writer = cv2.VideoWriter(dest, cv2.VideoWriter_fourcc(*TIMELAPSE_FOURCC), fps, resolution)
+            elif (image.shape[1], image.shape[0]) != resolution:
+                image = cv2.resize(image, resolution)
+            video_writer.write(image)
+            count += 1
+    finally:
+        if video_writer != None:
+            video_writer.release()
+    return count
@@ -0,0 +1,132 @@
+import os
+import time
+import shutil
+import base64
+import datetime
+import urllib.request
+import urllib.error
+from threading import Thread, Lock
+from logging import getLogger
+from doorcapture import Capture, list_events
+
+UPLOAD_TIMEOUT = 60
+UPLOAD_RETRY_MIN = 5
+METADATA_FILE = 'metadata.yaml'
+IMAGES_DIR = 'images'
+
+class Uploader():
+
+    logger = getLogger('doorcam.upload')
+
+    def __init__(self, capture_paths, url, mode='put', username=None, password=None, delete_local=False, retry_max=3600, callbacks:set=None):
+        self.url = url.rstrip('/')
+        self.mode = mode
+        self.auth = None
+        if username != None:
+            self.auth = 'Basic ' + base64.b64encode(f'{username}:{password or ""}'.encode()).decode()
+        self.delete_local = delete_local
+        self.retry_max = retry_max
+        self.callbacks = callbacks
+        self.lock = Lock()
+        self.queue = self.find_pending(capture_paths)
+        self.upload_thread = Thread(target=self.upload_loop, daemon=True)
+        self.upload_thread.start()
+
+    def find_pending(self, capture_paths):
+        pending = []
+        for timestamp, path in list_events(capture_paths):
+            metadata = Capture.load_metadata(path)
+            if 'frames' in metadata and 'uploaded' not in metadata:
+                pending.append(path)
+        if len(pending) > 0:
+            self.logger.info(f'Found {len(pending)} events not yet uploaded, queueing them')
+        return pending
+
+    def add(self, path):
+        with self.lock:
+            self.queue.append(path)
+
+    def upload_loop(self):
+        backoff = UPLOAD_RETRY_MIN
+        while True:
+            while len(self.queue) == 0:
+                time.sleep(1)
+            path = self.queue[0]
+            if not os.path.isdir(path):
+                self.logger.debug(f'{path} no longer exists, skipping upload')
+                with self.lock:
+                    self.queue.remove(path)
+                continue
+            try:
+                self.upload(path)
+            except Exception as e:
+                self.logger.error(f'Could not upload {path}, retrying it after the other pending events in {backoff} seconds: {e}')
+                with self.lock:
+                    self.queue.remove(path)
+                    self.queue.append(path)
+                time.sleep(backoff)
+                backoff = min(backoff * 2, self.retry_max)
+                continue
+            backoff = UPLOAD_RETRY_MIN
+            with self.lock:
+                self.queue.remove(path)
+            self.trigger_callbacks(os.path.basename(path))
+
+    def upload(self, path):
+        event = os.path.basename(path)
+        metadata = Capture.load_metadata(path)
+        media = self.find_media(path, metadata)
+        if self.mode == 'webdav':
+            self.mkcol(f'{self.url}/{event}/')
+            if any(x.startswith(f'{IMAGES_DIR}/') for x in media):
+                self.mkcol(f'{self.url}/{event}/{IMAGES_DIR}/')
+        for filename in media + [METADATA_FILE]:
+            if os.path.isfile(os.path.join(path, filename)):
+                self.put(f'{self.url}/{event}/{filename}', os.path.join(path, filename))
+        if len(media) == 0:
+            self.logger.warning(f'{event} has no video or images, only its metadata was uploaded')
+        if self.delete_local and len(media) > 0:
+            shutil.rmtree(path)
+            self.logger.info(f'Uploaded {event} to {self.url} and deleted the local copy')
+        else:
+            Capture.update_metadata(path, {'uploaded': datetime.datetime.now()})
+            self.logger.info(f'Uploaded {event} to {self.url}')
+
+    def find_media(self, path, metadata):
+        video = metadata.get('video')
+        if video != None and os.path.isfile(os.path.join(path, video)):
+            return [video]
+        imgpath = os.path.join(path, IMAGES_DIR)
+        if not os.path.isdir(imgpath):
+            return []
+        return [f'{IMAGES_DIR}/{x}' for x in sorted(os.listdir(imgpath)) if x[-4:].lower() == '.jpg']
+
+    def put(self, url, filepath):
+        with open(filepath, 'rb') as data:
+            request = urllib.request.Request(url, data=data, method='PUT')
+            request.add_header('Content-Length', str(os.path.getsize(filepath)))
+            self.send(request)
+
+    def mkcol(self, url):
+        try:
+            self.send(urllib.request.Request(url, method='MKCOL'))
+        except urllib.error.HTTPError as e:
+            if e.code != 405:
+                raise
+
+    def send(self, request):
+        if self.auth != None:
+            request.add_header('Authorization', self.auth)
+        with urllib.request.urlopen(request, timeout=UPLOAD_TIMEOUT) as response:
+            response.read()
+
+    def trigger_callbacks(self, event):
+        if self.callbacks != None:
+            for callback in self.callbacks:
+                Thread(target=callback, args=(event, ), daemon=True).start()
+
+    def add_callback(self, callback):
+        if self.callbacks != None:
+            self.callbacks.add(callback)
+        else:
+            self.callbacks = set((callback,))
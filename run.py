@@ -1,111 +1,656 @@
-from ast import parse
-import cv2
-import numpy as np
-from threading import Thread
-from doorscreen import *
-from doorcam import *
-from doorstream import *
-from dooranalyzer import *
-from doorconfig import *
-import time
-from functools import partial
-import argparse
-from logging import getLogger, StreamHandler, Formatter, DEBUG, INFO
-from systemd import journal
-import psutil
-from doorcapture import *
-
-def setup_logger(debug=False):
-    logger = getLogger('doorcam')
-    stream_formatter = Formatter('%(asctime)s [%(levelname)s] %(name)s: %(message)s')
-    journald_handler = journal.JournalHandler()
-    stream_handler = StreamHandler()
-    stream_handler.setFormatter(stream_formatter)
-    if debug:
-        logger.setLevel(DEBUG)
-        journald_handler.setLevel(DEBUG)
-        stream_handler.setLevel(DEBUG)
-    else:
-        logger.setLevel(INFO)
-        journald_handler.setLevel(INFO)
-        stream_handler.setLevel(INFO)
-    if psutil.Process(os.getpid()).ppid() == 1:
-        logger.addHandler(journald_handler)
-    else:
-        logger.addHandler(stream_handler)
-    return logger
-
-def parse_args():
-    parser = argparse.ArgumentParser()
-    parser.add_argument('-c', '--config', default=os.path.join(os.path.dirname('__file__'), 'config.yaml'), metavar='config.yaml')
-    parser.add_argument('-d', '--debug', action='store_true')
-    parser.add_argument('-f', '--fps', action='store_true')
-    return parser.parse_args()
-
-def main():
-    args = parse_args()
-    config = Config(args.config)
-    logger = setup_logger(args.debug)
-    cam = Camera(
-        config['camera']['index'], 
-        config['camera']['resolution'], 
-        config['camera']['rotation_const'], 
-        config['camera']['max_fps'], 
-        config['camera']['fourcc'], 
-        config['camera']['K'], 
-        config['camera']['D']
-    )
-    screen = Screen(
-        cam, 
-        config['screen']['resolution'], 
-        config['screen']['rotation_const'], 
-        config['screen']['framebuffer_device'], 
-        config['screen']['backlight_device'], 
-        config['screen']['touch_device'], 
-        config['screen']['color_conv_const'], 
-        config['screen']['dtype_np'], 
-        config['screen']['activation_period'], 
-        config['screen']['undistort'], 
-        config['screen']['undistort_balance']
-    )
-    analyzer_callbacks = set((screen.play_camera, ))
-    if config['capture']['enable']:
-        try:
-            capture = Capture(
-                cam,
-                config['capture']['preroll'],
-                config['capture']['postroll'],
-                config['capture']['path'],
-                config['capture']['timestamp'],
-                config['capture']['rotation_const'],
-                config['capture']['video_encode'],
-                config['capture']['keep_images'],
-                config['capture']['trim_old'],
-                config['capture']['trim_limit'],
-            )
-            analyzer_callbacks.add(capture.trigger_capture)
-        except Exception as e:
-            logger.error(e)
-    analyzer = Analyzer(
-        cam,
-        config['analyzer']['max_fps'],
-        config['analyzer']['delta_threshold'],
-        config['analyzer']['contour_minimum_area'],
-        config['analyzer']['undistort'],
-        config['analyzer']['undistort_balance'],
-        analyzer_callbacks
-    )
-    stream_handler = partial(MJPGHandler, cam)
-    server = MJPGServer((config['stream']['ip'], config['stream']['port']), stream_handler)
-    if args.fps:
-        http_thread = Thread(target=server.serve_forever, daemon=True)
-        http_thread.start()
-        while True:
-            logger.info(f'Cam: {cam.fps} | Screen: {screen.fps} | Analyzer: {analyzer.fps}')
-            time.sleep(1)
-    else:
-        server.serve_forever()
-    
-
-if __name__ == '__main__':
+from ast import parse
+import cv2
+import numpy as np
+from threading import Thread
+from doorscreen import *
+from doorcam import *
+from doorstream import *
+from dooranalyzer import *
+from doorconfig import *
+import time
+from functools import partial
+import argparse
+from logging import getLogger, StreamHandler, Formatter, DEBUG, INFO
+from logging.handlers import TimedRotatingFileHandler, RotatingFileHandler
+try:
+    from systemd import journal
+except ImportError:
+    journal = None
+import psutil
+import signal
+import socket
+import glob
+import sys
+import subprocess
+from evdev import list_devices
+from doorcapture import *
+from doordiagnostics import run_diagnostics, run_check, check_encoder
+from doortimelapse import generate_timelapse
+from doorarm import ArmState
+from doorevents import EventSocket
+from doorlibrary import EventLibrary, event_size
+from doorreplay import replay
+from doorupload import Uploader
+import datetime
+
+LIVE_SETTINGS = {
+    'analyzer': ('delta_threshold', 'contour_minimum_area', 'profiles', 'cooldown', 'motion_end_frames', 'learning_rate', 'reject_lighting_changes', 'lighting_change_threshold', 'blur_size', 'erode_iterations', 'dilate_iterations'),
+    'capture': ('max_duration', 'max_events_kept', 'min_events_kept', 'postroll', 'postroll_extension_fraction', 'postroll_max', 'postroll_scale_area', 'timestamp', 'timestamp_color', 'timestamp_format', 'timestamp_position', 'timestamp_utc', 'trim_limit'),
+    'screen': ('activation_period', 'notification_period')
+}
+SECRET_SETTINGS = {
+    'stream': ('auth_password', 'auth_token'),
+    'upload': ('password', )
+}
+SECRET_MASK = '********'
+
+def setup_logger(debug=False, log_config=None):
+    logger = getLogger('doorcam')
+    stream_formatter = Formatter('%(asctime)s [%(levelname)s] %(name)s: %(message)s')
+    stream_handler = StreamHandler()
+    stream_handler.setFormatter(stream_formatter)
+    under_systemd = psutil.Process(os.getpid()).ppid() == 1 or 'JOURNAL_STREAM' in os.environ
+    if under_systemd and journal != None:
+        handler = journal.JournalHandler(SYSLOG_IDENTIFIER='doorcam')
+    else:
+        handler = stream_handler
+    if debug:
+        logger.setLevel(DEBUG)
+    else:
+        logger.setLevel(INFO)
+        if log_config != None:
+            for name, level in log_config['levels'].items():
+                getLogger(f'doorcam.{name}').setLevel(level.upper())
+    logger.addHandler(handler)
+    if log_config != None and log_config['file'] != None:
+        if log_config['rotation'] == 'daily':
+            file_handler = TimedRotatingFileHandler(log_config['file'], when='midnight', backupCount=log_config['backups'])
+        else:
+            file_handler = RotatingFileHandler(log_config['file'], maxBytes=int(log_config['max_size'] * 1024 * 1024), backupCount=log_config['backups'])
+        file_handler.setFormatter(stream_formatter)
+        logger.addHandler(file_handler)
+    if under_systemd and journal == None:
+        logger.warning('python-systemd is not installed, logging to stderr instead of the journal')
+    return logger
+
+def sd_notify(state):
+    path = os.environ.get('NOTIFY_SOCKET')
+    if not path:
+        return
+    if path.startswith('@'):
+        path = '\0' + path[1:]
+    try:
+        with socket.socket(socket.AF_UNIX, socket.SOCK_DGRAM) as notify_socket:
+            notify_socket.connect(path)
+            notify_socket.sendall(state.encode())
+    except OSError as e:
+        getLogger('doorcam').error(f'Could not send {state} to systemd at {path}: {e}')
+
+def watchdog_loop(interval, cam):
+    logger = getLogger('doorcam.health')
+    last_id = None
+    while True:
+        frame_id = cam.frame_id
+        if frame_id != last_id:
+            sd_notify('WATCHDOG=1')
+            last_id = frame_id
+        else:
+            logger.warning(f'No new camera frame in {interval:.0f} seconds, withholding the systemd watchdog ping')
+        time.sleep(interval)
+
+def health_loop(cam, stall_shutdown):
+    logger = getLogger('doorcam.health')
+    while True:
+        stalled = time.time() - cam.last_frame_time
+        if stalled > stall_shutdown:
+            logger.critical(f'No frames from the camera for {stalled:.0f} seconds after {cam.reconnect_count} reconnect attempts, exiting so the service can be restarted')
+            sd_notify('STOPPING=1')
+            os._exit(1)
+        time.sleep(1)
+
+def encoder_check_loop(config, interval, screen, events):
+    logger = getLogger('doorcam.health')
+    healthy = True
+    while True:
+        time.sleep(interval)
+        ok, message = run_check(check_encoder, config)
+        if ok != healthy:
+            healthy = ok
+            if ok:
+                logger.info(f'Video encoder recovered: {message}')
+            else:
+                logger.error(f'Video encoder check failed, events may not be encoded: {message}')
+                if screen != None:
+                    screen.notify('Video encoder failed')
+            if events != None:
+                events.publish('encoder_health', healthy=ok, message=message)
+        else:
+            logger.debug(f'Video encoder check {"passed" if ok else "failed"}: {message}')
+
+def stats_loop(interval, events, cameras, screen, capture_paths):
+    motion_counts = {camera_id: analyzer.motion_count if analyzer != None else 0 for camera_id, (cam, analyzer, capture) in cameras.items()}
+    while True:
+        time.sleep(interval)
+        stats = {}
+        for camera_id, (cam, analyzer, capture) in cameras.items():
+            camera_stats = {'camera_fps': cam.fps, 'camera_gaps': cam.gap_count}
+            if analyzer != None:
+                camera_stats['analyzer_fps'] = analyzer.fps
+                camera_stats['motion_per_minute'] = (analyzer.motion_count - motion_counts[camera_id]) * 60 / interval
+                motion_counts[camera_id] = analyzer.motion_count
+            if capture != None:
+                camera_stats['active_captures'] = len(capture.get_active_capture_details())
+                camera_stats['post_process_queue'] = len(capture.post_process_queue)
+                camera_stats['preroll'] = capture.queue.stats()
+                camera_stats['capture_missed_frames'] = capture.missed_count
+                camera_stats['post_process_failed'] = capture.failed_count
+            if camera_id == None:
+                stats.update(camera_stats)
+            else:
+                stats.setdefault('cameras', {})[camera_id] = camera_stats
+        if screen != None:
+            stats['screen'] = screen.stats()
+        event_list = list_events(capture_paths)
+        stats['stored_events'] = len(event_list)
+        stats['storage_bytes'] = sum(event_size(path) for timestamp, path in event_list)
+        events.publish('stats', **stats)
+
+def log_status(logger, cam, screen, analyzer, stream_stats, capture, arm=None, extra_cameras={}):
+    status = [f'Cam: {cam.fps}']
+    if screen != None:
+        status.append(f'Screen: {screen.fps} ({"on" if screen.screen_on else "off"}, rendered {screen.rendered_count}, skipped {screen.skipped_count})')
+    if analyzer != None:
+        status.append(f'Analyzer: {analyzer.fps} (skipped {analyzer.skipped_count}, late {analyzer.late_count}{", warming up" if analyzer.warmup_until != None else ""})')
+    status.append(f'Stream clients: {stream_stats.clients} (peak {stream_stats.peak_clients})')
+    status.append(f'Cam reconnects: {cam.reconnect_count} | Cam frame gaps: {cam.gap_count}')
+    if analyzer != None:
+        status.append(f'Motion suppressed: {analyzer.suppressed_count} | Lighting changes rejected: {analyzer.rejected_count}')
+    logger.info(' | '.join(status))
+    for address, fps in stream_stats.client_fps.copy().items():
+        logger.info(f'Stream client {address[0]}:{address[1]}: {fps} (skipped {stream_stats.client_skipped.get(address, 0)})')
+    if arm != None:
+        logger.info(f'{"Armed" if arm.armed else "Disarmed"}{f", re-arming in {int(arm.rearm_time - time.time())} seconds" if arm.rearm_time != None else ""} | Camera {"connected" if cam.connected else "disconnected"}')
+    if capture != None:
+        preroll = capture.queue.stats()
+        logger.info(f'Preroll: {preroll["length"]} frames ({preroll["spooled"]} on disk, {preroll["memory"] / (1024 * 1024):.1f} MB in memory) over {preroll["span"]:.1f}s | Pushed: {preroll["pushed"]} | Evicted: {preroll["evicted"]}')
+        for event in capture.get_active_capture_details():
+            logger.info(f'Capturing {event["event"]}: {event["duration"]:.1f}s, {event["frames"]} frames, {event["postroll_remaining"]:.1f} {capture.roll_unit} of postroll remaining')
+        logger.info(f'Post-process queue: {len(capture.post_process_queue)} events ({capture.failed_count} failed) | Frames missed by captures: {capture.missed_count}')
+    for camera_id, (extra_cam, extra_analyzer, extra_capture) in extra_cameras.items():
+        status = [f'Camera {camera_id}: {extra_cam.fps} ({"connected" if extra_cam.connected else "disconnected"}, {extra_cam.reconnect_count} reconnects, {extra_cam.gap_count} frame gaps)']
+        status.append(f'Analyzer: {extra_analyzer.fps} (skipped {extra_analyzer.skipped_count}, late {extra_analyzer.late_count}{", warming up" if extra_analyzer.warmup_until != None else ""})')
+        if extra_capture != None:
+            status.append(f'Capturing: {len(extra_capture.get_active_capture_details())} events | Post-process queue: {len(extra_capture.post_process_queue)} events ({extra_capture.failed_count} failed)')
+        logger.info(' | '.join(status))
+
+def manual_capture(captures):
+    logger = getLogger('doorcam')
+    captures = [capture for capture in captures if capture != None]
+    if len(captures) == 0:
+        logger.warning('Manual capture requested but capturing is disabled')
+        return
+    logger.info('Manual capture requested')
+    for capture in captures:
+        capture.trigger_capture()
+
+def shutdown(signum, captures, events):
+    getLogger('doorcam').info(f'Received signal {signum}, shutting down')
+    sd_notify('STOPPING=1')
+    for capture in captures:
+        if capture != None:
+            capture.stop()
+    if events != None:
+        events.close()
+    sys.exit(0)
+
+def update_live_settings(logger, prefix, config, new_config, sections):
+    for section in sections:
+        for key, value in new_config[section].items():
+            if key.endswith('_const') or str(value) == str(config[section].get(key)):
+                continue
+            if key in LIVE_SETTINGS.get(section, ()):
+                logger.info(f'Applying {prefix}{section}.{key}: {config[section].get(key)} -> {value}')
+                config[section][key] = value
+            else:
+                logger.warning(f'Change to {prefix}{section}.{key} requires restart, ignoring')
+    config['analyzer']['profiles_const'] = new_config['analyzer']['profiles_const']
+
+def reload_config(path, config, screen, cameras):
+    logger = getLogger('doorcam.reload')
+    logger.info(f'Reloading config from {path}')
+    try:
+        new_config = Config(path, save=False)
+    except Exception as e:
+        logger.error(f'Could not reload config, keeping current settings: {e}')
+        return
+    if set(new_config.camera_configs) != set(config.camera_configs):
+        logger.warning('Adding or removing cameras requires restart, ignoring')
+    for camera_id, camera_config in config.all_configs():
+        if camera_id == None:
+            update_live_settings(logger, '', camera_config, new_config, [section for section in new_config if section != 'cameras'])
+        elif camera_id in new_config.camera_configs:
+            update_live_settings(logger, f'cameras.{camera_id}.', camera_config, new_config.camera_configs[camera_id], CAMERA_SECTIONS)
+        if camera_id in cameras:
+            cam, analyzer, capture = cameras[camera_id]
+            apply_live_settings(camera_config, analyzer, capture)
+    if screen != None:
+        screen.activation_period = config['screen']['activation_period']
+        screen.notification_period = config['screen']['notification_period']
+
+def apply_live_settings(config, analyzer, capture):
+    if analyzer != None:
+        analyzer.set_sensitivity(
+            config['analyzer']['delta_threshold'],
+            config['analyzer']['contour_minimum_area'],
+            config['analyzer']['profiles_const']
+        )
+        analyzer.cooldown = config['analyzer']['cooldown']
+        analyzer.motion_end_frames = config['analyzer']['motion_end_frames']
+        analyzer.learning_rate = config['analyzer']['learning_rate']
+        analyzer.reject_lighting_changes = config['analyzer']['reject_lighting_changes']
+        analyzer.lighting_change_threshold = config['analyzer']['lighting_change_threshold']
+        analyzer.blur_size = config['analyzer']['blur_size']
+        analyzer.erode_iterations = config['analyzer']['erode_iterations']
+        analyzer.dilate_iterations = config['analyzer']['dilate_iterations']
+    if capture != None:
+        capture.max_duration = config['capture']['max_duration']
+        capture.max_events_kept = config['capture']['max_events_kept']
+        capture.min_events_kept = config['capture']['min_events_kept']
+        capture.postroll = config['capture']['postroll']
+        capture.postroll_max = config['capture']['postroll_max']
+        capture.postroll_scale_area = config['capture']['postroll_scale_area']
+        capture.postroll_extension_fraction = config['capture']['postroll_extension_fraction']
+        capture.timestamp = config['capture']['timestamp']
+        capture.timestamp_position = config['capture']['timestamp_position']
+        capture.timestamp_format = config['capture']['timestamp_format']
+        capture.timestamp_utc = config['capture']['timestamp_utc']
+        capture.timestamp_color = tuple(reversed(config['capture']['timestamp_color']))
+        capture.trim_limit = config['capture']['trim_limit']
+
+def print_devices():
+    print('Video capture devices:')
+    for path in sorted(glob.glob('/dev/video*'), key=lambda x: int(x[len('/dev/video'):] or 0)):
+        print(f'  {path} (index {path[len("/dev/video"):]})')
+        try:
+            formats = subprocess.run(['v4l2-ctl', '-d', path, '--list-formats-ext'], capture_output=True, text=True, timeout=5).stdout
+            for line in formats.splitlines():
+                line = line.strip()
+                if line.startswith('[') or line.startswith('Size'):
+                    print(f'    {line}')
+        except FileNotFoundError:
+            cap = cv2.VideoCapture(path, cv2.CAP_V4L2)
+            if cap.isOpened():
+                fourcc = int(cap.get(cv2.CAP_PROP_FOURCC)).to_bytes(4, 'little').decode(errors='replace')
+                print(f'    {fourcc} {int(cap.get(cv2.CAP_PROP_FRAME_WIDTH))}x{int(cap.get(cv2.CAP_PROP_FRAME_HEIGHT))} @ {cap.get(cv2.CAP_PROP_FPS)} fps (install v4l2-ctl to list all formats)')
+            cap.release()
+        except Exception as e:
+            print(f'    Could not query formats: {e}')
+    print('Touch input devices:')
+    for path in sorted(list_devices()):
+        dev = InputDevice(path)
+        capabilities = dev.capabilities()
+        if ecodes.BTN_TOUCH in capabilities.get(ecodes.EV_KEY, []) and ecodes.EV_ABS in capabilities:
+            x = dev.absinfo(ecodes.ABS_X)
+            y = dev.absinfo(ecodes.ABS_Y)
+            print(f'  {path}: {dev.name} (x {x.min}-{x.max}, y {y.min}-{y.max})')
+
+def start_camera(config):
+    return Camera(
+        config['camera']['index'], 
+        config['camera']['resolution'], 
+        config['camera']['rotation_const'], 
+        config['camera']['max_fps'], 
+        config['camera']['fourcc'], 
+        config['camera']['K'], 
+        config['camera']['D'],
+        config['camera']['reconnect_timeout'],
+        config['camera']['reconnect_backoff_max'],
+        config['camera']['jpeg_quality'],
+        config['camera']['strict_format']
+    )
+
+def start_capture(config, cam):
+    return Capture(
+        cam,
+        config['capture']['preroll'],
+        config['capture']['postroll'],
+        config['capture']['postroll_max'],
+        config['capture']['postroll_scale_area'],
+        config['capture']['postroll_extension_fraction'],
+        config['capture']['path'],
+        config['capture']['timestamp'],
+        config['capture']['rotation_const'],
+        config['capture']['video_encode'],
+        config['capture']['keep_images'],
+        config['capture']['trim_old'],
+        config['capture']['trim_limit'],
+        config['capture']['min_events_kept'],
+        config['capture']['max_events_kept'],
+        config['capture']['fsync_interval'],
+        config['capture']['fallback_path'],
+        config['capture']['max_duration'],
+        config['capture']['dedup'],
+        config['capture']['dedup_threshold'],
+        config['capture']['preroll_eviction'],
+        config['capture']['preroll_memory'],
+        config['capture']['preroll_memory_budget'],
+        config['capture']['container'],
+        config['capture']['roll_unit'],
+        config['capture']['draw_motion_boxes'],
+        config['capture']['timestamp_position'],
+        config['capture']['timestamp_format'],
+        config['capture']['timestamp_utc'],
+        config['capture']['timestamp_color'],
+        config['capture']['label'],
+        config['capture']['label_position'],
+        config['capture']['logo_path'],
+        config['capture']['logo_position'],
+        config['capture']['name_template'],
+        config['capture']['crop_region'],
+        config['capture']['post_process_timeout'],
+        config['capture']['post_process_retries'],
+        config['capture']['post_process_workers'],
+    )
+
+def start_analyzer(config, cam, callbacks):
+    return Analyzer(
+        cam,
+        config['analyzer']['max_fps'],
+        config['analyzer']['delta_threshold'],
+        config['analyzer']['contour_minimum_area'],
+        config['analyzer']['undistort'],
+        config['analyzer']['undistort_balance'],
+        config['analyzer']['cooldown'],
+        config['analyzer']['motion_end_frames'],
+        config['analyzer']['warmup'],
+        config['analyzer']['learning_rate'],
+        config['analyzer']['reject_lighting_changes'],
+        config['analyzer']['lighting_change_threshold'],
+        config['analyzer']['blur_size'],
+        config['analyzer']['erode_iterations'],
+        config['analyzer']['dilate_iterations'],
+        config['analyzer']['profiles_const'],
+        callbacks
+    )
+
+def start_extra_camera(camera_id, config, arm, events):
+    cam = start_camera(config)
+    analyzer_callbacks = set()
+    capture = None
+    if config['capture']['enable']:
+        capture = start_capture(config, cam)
+        analyzer_callbacks.add(arm.gate(capture.trigger_capture))
+        if events != None:
+            capture.add_start_callback(lambda event: events.publish('capture_started', event=event, camera=camera_id))
+            capture.add_error_callback(lambda message: events.publish('capture_error', message=message, camera=camera_id))
+    if events != None:
+        cam.add_status_callback(lambda connected: events.publish('camera_status', connected=connected, camera=camera_id))
+        analyzer_callbacks.add(lambda area: events.publish('motion_detected', area=area, camera=camera_id))
+    analyzer = start_analyzer(config, cam, analyzer_callbacks)
+    if capture != None and config['capture']['draw_motion_boxes']:
+        analyzer.add_box_callback(capture.set_motion_boxes)
+    if events != None:
+        analyzer.add_end_callback(lambda duration: events.publish('motion_ended', duration=duration, camera=camera_id))
+    getLogger('doorcam').info(f'Started camera {camera_id} from index {config["camera"]["index"]}, capturing to {config["capture"]["path"]}')
+    return cam, analyzer, capture
+
+def parse_local_time(value):
+    timestamp = datetime.datetime.fromisoformat(value)
+    if timestamp.tzinfo != None:
+        timestamp = timestamp.astimezone().replace(tzinfo=None)
+    return timestamp
+
+def parse_args():
+    parser = argparse.ArgumentParser()
+    parser.add_argument('-c', '--config', default=os.path.join(os.path.dirname('__file__'), 'config.yaml'), metavar='config.yaml')
+    parser.add_argument('-d', '--debug', action='store_true')
+    parser.add_argument('-f', '--fps', action='store_true')
+    parser.add_argument('--diagnostics', action='store_true', help='check each device and the capture path, print a summary and exit non-zero on a critical failure')
+    parser.add_argument('--list-devices', action='store_true', help='list video capture and touch input devices and exit')
+    parser.add_argument('--export', metavar='EVENT', help='bundle the named event, with its video, images and metadata, into a zip archive and exit')
+    parser.add_argument('--export-dest', default='.', metavar='PATH', help='file or directory to write the --export archive to')
+    parser.add_argument('--timelapse', nargs=2, metavar=('START', 'END'), type=parse_local_time, help='encode a timelapse of the events between two ISO 8601 times, i.e. 2022-05-01T00:00, in local time unless they give a UTC offset, and exit')
+    parser.add_argument('--timelapse-dest', default='timelapse.mp4', metavar='PATH', help='file to write the --timelapse video to')
+    parser.add_argument('--timelapse-fps', default=10, type=int, metavar='FPS', help='frame rate of the --timelapse video')
+    parser.add_argument('--timelapse-camera', metavar='ID', help='encode the --timelapse from this camera under cameras instead of the main one')
+    parser.add_argument('--timelapse-interval', type=float, metavar='SECONDS', help='sample a frame every this many seconds of captured footage instead of one frame from the middle of each event')
+    parser.add_argument('--trim', action='store_true', help='trim old events once as configured, print what was trimmed and exit')
+    parser.add_argument('--trim-limit', type=float, metavar='DAYS', help='trim events older than this instead of the configured trim_limit')
+    parser.add_argument('--dry-run', action='store_true', help='only print what --trim would delete')
+    parser.add_argument('--verify', action='store_true', help='check the capture paths for orphaned videos, events with missing, empty or mis-sized videos, zero-frame events, unprocessed images and unrecognized names, print what was found and exit')
+    parser.add_argument('--repair', action='store_true', help='with --verify, register orphaned videos as events and prune zero-frame and empty events')
+    parser.add_argument('--reconcile', action='store_true', help='same as --verify --repair')
+    parser.add_argument('--replay', metavar='PATH', help='run a directory of JPEG frames, or an event saved with keep_images, through the analyzer with the configured settings, print the largest contour area of each frame and whether it would have triggered, and exit')
+    parser.add_argument('--print-config', action='store_true', help='print the effective config, including environment overrides but with passwords and tokens masked, and exit')
+    return parser.parse_args()
+
+def main():
+    args = parse_args()
+    if args.list_devices:
+        print_devices()
+        return
+    one_shot = any((args.print_config, args.diagnostics, args.export, args.trim, args.verify, args.reconcile, args.replay, args.timelapse))
+    config = Config(args.config, save=not one_shot)
+    if args.print_config:
+        config.clear_constants()
+        for section, keys in SECRET_SETTINGS.items():
+            for key in keys:
+                if config[section][key] != None:
+                    config[section][key] = SECRET_MASK
+        print(yaml.safe_dump(dict(config)))
+        return
+    if args.diagnostics:
+        sys.exit(0 if run_diagnostics(config) else 1)
+    if args.export:
+        archive, size = export_event(find_event(config.capture_paths(), args.export), args.export_dest)
+        print(f'Exported {args.export} to {archive} ({size} bytes)')
+        return
+    if args.trim:
+        trimmed = []
+        for camera_id, camera_config in config.all_configs():
+            trim_limit = args.trim_limit if args.trim_limit != None else camera_config['capture']['trim_limit']
+            trimmed += trim_events((camera_config['capture']['path'], camera_config['capture']['fallback_path']), trim_limit, camera_config['capture']['min_events_kept'], camera_config['capture']['max_events_kept'], args.dry_run)
+        for path, reason in trimmed:
+            print(f'{path}: {reason}')
+        print(f'{"Would trim" if args.dry_run else "Trimmed"} {len(trimmed)} events')
+        return
+    if args.reconcile:
+        args.verify = args.repair = True
+    if args.verify:
+        findings = []
+        for camera_id, camera_config in config.all_configs():
+            findings += verify_events((camera_config['capture']['path'], camera_config['capture']['fallback_path']), args.repair, camera_config['capture']['post_process_retries'], camera_config['capture']['name_template'], camera_config['capture']['label'])
+        for path, finding in findings:
+            print(f'{path}: {finding}')
+        print(f'{len(findings)} findings')
+        return
+    if args.replay:
+        total = triggered = 0
+        for filename, largest, boxes in replay(config, args.replay):
+            total += 1
+            if len(boxes) > 0:
+                triggered += 1
+            print(f'{filename}  {largest:>10.0f}  {"MOTION " + str(len(boxes)) if len(boxes) > 0 else ""}')
+        print(f'{triggered} of {total} frames would have triggered')
+        return
+    if args.timelapse:
+        timelapse_config = config if args.timelapse_camera == None else config.camera_configs.get(args.timelapse_camera)
+        if timelapse_config == None:
+            sys.exit(f'Unknown camera {args.timelapse_camera}, expected one of {", ".join(config.camera_configs) or "none"}')
+        count = generate_timelapse((timelapse_config['capture']['path'], timelapse_config['capture']['fallback_path']), args.timelapse[0], args.timelapse[1], args.timelapse_dest, args.timelapse_fps, args.timelapse_interval)
+        print(f'Wrote {count} frames to {args.timelapse_dest}')
+        return
+    logger = setup_logger(args.debug, config['logging'])
+    cam = start_camera(config)
+    degraded = []
+    screen = None
+    try:
+        screen = Screen(
+            cam, 
+            config['screen']['resolution'], 
+            config['screen']['rotation_const'], 
+            config['screen']['framebuffer_device'], 
+            config['screen']['backlight_device'], 
+            config['screen']['touch_device'], 
+            config['screen']['color_conv_const'], 
+            config['screen']['dtype_np'], 
+            config['screen']['activation_period'], 
+            config['screen']['undistort'], 
+            config['screen']['undistort_balance'],
+            config['screen']['swipe_distance'],
+            config['screen']['long_press_time'],
+            config['screen']['touch_zones'],
+            config['screen']['idle_screen'],
+            config['capture']['path'] if os.path.isdir(config['capture']['path']) else '.',
+            config['screen']['notification_period'],
+            config['screen']['frame_policy'],
+            config['screen']['wake_on'],
+            config['screen']['motion_wake_disarmed'],
+            config['screen']['fade_time'],
+            config['screen']['backlog_drop'],
+            config['screen']['backlog_block_time']
+        )
+    except Exception as e:
+        logger.error(f'Could not start the screen, continuing without it: {e}')
+        degraded.append('screen')
+    arm = ArmState(config['capture']['rearm_timeout'], config['capture']['arm_schedule_const'], config['capture']['arm_timezone_const'])
+    analyzer_callbacks = set()
+    if screen != None:
+        cam.add_status_callback(lambda connected: screen.notify('Camera reconnected' if connected else 'Camera disconnected'))
+        arm.add_callback(screen.set_armed)
+        screen.add_zone_callback(lambda zone: arm.toggle() if zone == 'arm' else None)
+        analyzer_callbacks.add(screen.play_camera)
+    events = None
+    if config['events']['socket_path'] != None:
+        try:
+            events = EventSocket(config['events']['socket_path'], config['events']['quiet_period'])
+            cam.add_status_callback(lambda connected: events.publish('camera_status', connected=connected))
+            arm.add_callback(lambda armed: events.publish('arm_state_changed', armed=armed))
+            analyzer_callbacks.add(lambda area: events.publish('motion_detected', area=area))
+        except Exception as e:
+            logger.error(e)
+    capture = None
+    if config['capture']['enable']:
+        try:
+            capture = start_capture(config, cam)
+            analyzer_callbacks.add(arm.gate(capture.trigger_capture))
+            if screen != None:
+                capture.add_start_callback(lambda event: screen.notify(f'Recording {event}'))
+                capture.add_error_callback(screen.notify)
+            if events != None:
+                capture.add_start_callback(lambda event: events.publish('capture_started', event=event))
+                capture.add_error_callback(lambda message: events.publish('capture_error', message=message))
+        except Exception as e:
+            logger.error(e)
+            degraded.append('capture')
+    analyzer = None
+    try:
+        analyzer = start_analyzer(config, cam, analyzer_callbacks)
+    except Exception as e:
+        logger.error(f'Could not start the analyzer, continuing without motion detection: {e}')
+        degraded.append('analyzer')
+    if analyzer != None and capture != None and config['capture']['draw_motion_boxes']:
+        analyzer.add_box_callback(capture.set_motion_boxes)
+    if analyzer != None and events != None:
+        analyzer.add_end_callback(lambda duration: events.publish('motion_ended', duration=duration))
+    extra_cameras = {}
+    for camera_id, camera_config in config.camera_configs.items():
+        try:
+            extra_cameras[camera_id] = start_extra_camera(camera_id, camera_config, arm, events)
+        except Exception as e:
+            logger.error(f'Could not start camera {camera_id}, continuing without it: {e}')
+            degraded.append(f'camera {camera_id}')
+    cameras = {None: (cam, analyzer, capture), **extra_cameras}
+    captures = [camera_capture for camera_cam, camera_analyzer, camera_capture in cameras.values() if camera_capture != None]
+    if len(captures) > 0 and config['upload']['url'] != None:
+        uploader = Uploader(
+            [path for camera_capture in captures for path in camera_capture.capture_paths()],
+            config['upload']['url'],
+            config['upload']['mode'],
+            config['upload']['username'],
+            config['upload']['password'],
+            config['upload']['delete_local'],
+            config['upload']['retry_max']
+        )
+        for camera_capture in captures:
+            camera_capture.add_finish_callback(uploader.add)
+        if events != None:
+            uploader.add_callback(lambda event: events.publish('event_uploaded', event=event))
+    hls_path = None
+    if config['stream']['mode'] == 'hls':
+        try:
+            hls = HLSEncoder(
+                cam,
+                config['stream']['hls_path'],
+                config['stream']['hls_segment_time'],
+                config['stream']['hls_window']
+            )
+            hls_path = hls.path
+        except Exception as e:
+            logger.error(f'Could not start the HLS encoder, streaming MJPEG only: {e}')
+            degraded.append('hls')
+    stream_auth = None
+    if config['stream']['auth_token'] != None or config['stream']['auth_username'] != None:
+        stream_auth = StreamAuth(
+            config['stream']['auth_username'],
+            config['stream']['auth_password'],
+            config['stream']['auth_token']
+        )
+    stream_stats = StreamStats(config['stream']['max_clients'])
+    stream_quality = None
+    if config['stream']['adaptive_quality']:
+        stream_quality = partial(
+            QualityAdapter,
+            config['stream']['adaptive_quality_threshold'],
+            config['stream']['adaptive_quality_reduced']
+        )
+    signal.signal(signal.SIGHUP, lambda signum, frame: Thread(target=reload_config, args=(args.config, config, screen, cameras), daemon=True).start())
+    stream_scaler = None
+    if len(config['stream']['resolutions_const']) > 0:
+        stream_scaler = FrameScaler(cam, config['stream']['resolutions_const'])
+    library = None
+    if config['stream']['events_api']:
+        library = EventLibrary(config.capture_paths(), captures, config['capture']['keep_deleted'])
+    restarts = {'camera': cam.restart}
+    if analyzer != None:
+        restarts['analyzer'] = analyzer.restart
+    for camera_id, (extra_cam, extra_analyzer, extra_capture) in extra_cameras.items():
+        restarts[f'camera/{camera_id}'] = extra_cam.restart
+        restarts[f'analyzer/{camera_id}'] = extra_analyzer.restart
+    stream_handler = partial(MJPGHandler, cam, hls_path, stream_auth, config['stream']['rotation_const'], stream_stats, stream_quality, stream_scaler, arm, library, restarts, events, config['stream']['frame_headers'])
+    try:
+        server = MJPGServer((config['stream']['ip'], config['stream']['port']), stream_handler, config['stream']['allowed_networks_const'], config['stream']['tls_cert'], config['stream']['tls_key'])
+    except OSError as e:
+        logger.critical(f'Could not start the stream server on {config["stream"]["ip"]}:{config["stream"]["port"]}: {e}')
+        sys.exit(1)
+    signal.signal(signal.SIGTERM, lambda signum, frame: shutdown(signum, captures, events))
+    if config['camera']['stall_shutdown']:
+        health_thread = Thread(target=health_loop, args=(cam, config['camera']['stall_shutdown']), daemon=True)
+        health_thread.start()
+    if capture != None and config['capture']['video_encode'] and config['capture']['encoder_check_interval']:
+        encoder_check_thread = Thread(target=encoder_check_loop, args=(config, config['capture']['encoder_check_interval'], screen, events), daemon=True)
+        encoder_check_thread.start()
+    if events != None and config['events']['stats_interval']:
+        stats_thread = Thread(target=stats_loop, args=(config['events']['stats_interval'], events, cameras, screen, config.capture_paths()), daemon=True)
+        stats_thread.start()
+    signal.signal(signal.SIGUSR1, lambda signum, frame: Thread(target=log_status, args=(logger, cam, screen, analyzer, stream_stats, capture, arm, extra_cameras), daemon=True).start())
+    signal.signal(signal.SIGUSR2, lambda signum, frame: Thread(target=manual_capture, args=(captures, ), daemon=True).start())
+    sd_notify('READY=1')
+    if len(degraded) > 0:
+        logger.warning(f'Running degraded without: {", ".join(degraded)}')
+        sd_notify(f'STATUS=Degraded, running without {", ".join(degraded)}')
+    if 'WATCHDOG_USEC' in os.environ:
+        watchdog_thread = Thread(target=watchdog_loop, args=(int(os.environ['WATCHDOG_USEC']) / 2000000, cam), daemon=True)
+        watchdog_thread.start()
+    if args.fps:
+        http_thread = Thread(target=server.serve_forever, daemon=True)
+        http_thread.start()
+        while True:
+            log_status(logger, cam, screen, analyzer, stream_stats, capture, extra_cameras=extra_cameras)
+            time.sleep(1)
+    else:
+        server.serve_forever()
+    
+
+if __name__ == '__main__':
     main()
\ No newline at end of file
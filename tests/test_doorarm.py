@@ -0,0 +1,50 @@
+import unittest
+import datetime
+from unittest import mock
+from doorarm import ArmState, schedule_armed
+
+WEEK = set(range(7))
+
+def window(start, end, days=WEEK):
+    return {'start': datetime.time(*start), 'end': datetime.time(*end), 'days': days}
+
+class ScheduleArmedTest(unittest.TestCase):
+
+    def test_inside_and_outside(self):
+        schedule = [window((8, 0), (18, 0))]
+        self.assertTrue(schedule_armed(schedule, datetime.datetime(2024, 1, 1, 12)))
+        self.assertFalse(schedule_armed(schedule, datetime.datetime(2024, 1, 1, 20)))
+
+    def test_window_past_midnight_belongs_to_its_start_day(self):
+        schedule = [window((22, 0), (6, 0), {0})]
+        self.assertTrue(schedule_armed(schedule, datetime.datetime(2024, 1, 1, 23)))
+        self.assertTrue(schedule_armed(schedule, datetime.datetime(2024, 1, 2, 5)))
+        self.assertFalse(schedule_armed(schedule, datetime.datetime(2024, 1, 1, 5)))
+
+class ArmStateTest(unittest.TestCase):
+
+    def make(self, **kwargs):
+        with mock.patch('doorarm.Thread'):
+            return ArmState(**kwargs)
+
+    def step(self, arm):
+        with mock.patch('doorarm.time.sleep', side_effect=StopIteration):
+            with self.assertRaises(StopIteration):
+                arm.arm_loop()
+
+    def test_manual_disarm_rearms_after_timeout(self):
+        arm = self.make(rearm_timeout=60)
+        arm.set_armed(False)
+        self.assertFalse(arm.armed)
+        self.assertIsNotNone(arm.rearm_time)
+
+    def test_scheduled_disarm_does_not_start_the_rearm_timer(self):
+        arm = self.make(rearm_timeout=60, schedule=[window((0, 0), (23, 59))])
+        self.assertTrue(arm.armed)
+        arm.schedule = [window((0, 0), (0, 1), set())]
+        self.step(arm)
+        self.assertFalse(arm.armed)
+        self.assertIsNone(arm.rearm_time)
+
+if __name__ == '__main__':
+    unittest.main()
@@ -0,0 +1,26 @@
+import unittest
+import cv2
+import numpy as np
+from doorcam import jpg_to_rgb
+
+@unittest.skipUnless(callable(getattr(cv2, 'imencode', None)), 'needs OpenCV')
+class JpgToRgbTest(unittest.TestCase):
+
+    def test_shape_and_channel_order(self):
+        bgr = np.zeros((4, 8, 3), dtype=np.uint8)
+        bgr[:, :] = (255, 0, 0)
+        ret, jpg = cv2.imencode('.jpg', bgr, (cv2.IMWRITE_JPEG_QUALITY, 100))
+        image, width, height = jpg_to_rgb(jpg)
+        self.assertEqual(image.shape, (4, 8, 3))
+        self.assertEqual((width, height), (8, 4))
+        red, green, blue = (int(x) for x in image[2, 4])
+        self.assertLess(red, 10)
+        self.assertLess(green, 10)
+        self.assertGreater(blue, 245)
+
+    def test_rejects_garbage(self):
+        with self.assertRaises(ValueError):
+            jpg_to_rgb(np.frombuffer(b'not a jpeg', dtype=np.uint8))
+
+if __name__ == '__main__':
+    unittest.main()
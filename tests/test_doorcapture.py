@@ -0,0 +1,207 @@
+import unittest
+import tempfile
+import os
+import zipfile
+import datetime
+from unittest import mock
+from doorcapture import TIME_FORMAT, Capture, CaptureQueue, export_event, trim_events, verify_events
+
+class ExportEventTest(unittest.TestCase):
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.event = os.path.join(self.tmp.name, '2024-01-01_12-00-00-000000')
+        os.makedirs(os.path.join(self.event, 'images'))
+        with open(os.path.join(self.event, '2024-01-01_12-00-00-000000.mp4'), 'wb') as video:
+            video.write(b'video')
+        with open(os.path.join(self.event, 'images', '2024-01-01_12-00-00-100000.jpg'), 'wb') as image:
+            image.write(b'image')
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def test_archives_every_file_under_the_event_name(self):
+        dest = os.path.join(self.tmp.name, 'out.zip')
+        archive, size = export_event(self.event, dest)
+        self.assertEqual(archive, dest)
+        self.assertEqual(size, os.path.getsize(dest))
+        with zipfile.ZipFile(dest) as exported:
+            self.assertEqual(sorted(exported.namelist()), [
+                '2024-01-01_12-00-00-000000/2024-01-01_12-00-00-000000.mp4',
+                '2024-01-01_12-00-00-000000/images/2024-01-01_12-00-00-100000.jpg',
+                '2024-01-01_12-00-00-000000/metadata.yaml'
+            ])
+            self.assertEqual(exported.read('2024-01-01_12-00-00-000000/2024-01-01_12-00-00-000000.mp4'), b'video')
+
+    def test_directory_destination_is_named_after_the_event(self):
+        dest = os.path.join(self.tmp.name, 'exports')
+        os.mkdir(dest)
+        archive, size = export_event(self.event, dest)
+        self.assertEqual(archive, os.path.join(dest, '2024-01-01_12-00-00-000000.zip'))
+        self.assertTrue(os.path.isfile(archive))
+
+    def test_records_last_access(self):
+        export_event(self.event, os.path.join(self.tmp.name, 'out.zip'))
+        self.assertIn('last_accessed', Capture.load_metadata(self.event))
+
+class TrimEventsTest(unittest.TestCase):
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        now = datetime.datetime.now()
+        self.new = [self.make_event(now - datetime.timedelta(hours=x)) for x in (1, 2)]
+        self.old = [self.make_event(now - datetime.timedelta(days=x)) for x in (3, 4, 5)]
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def make_event(self, timestamp):
+        path = os.path.join(self.tmp.name, timestamp.strftime(TIME_FORMAT))
+        os.mkdir(path)
+        return path
+
+    def trimmed(self, *args, **kwargs):
+        return [path for path, reason in trim_events([self.tmp.name], *args, **kwargs)]
+
+    def test_trims_events_older_than_the_limit(self):
+        self.assertEqual(sorted(self.trimmed(2)), sorted(self.old))
+        for path in self.old:
+            self.assertFalse(os.path.exists(path))
+        for path in self.new:
+            self.assertTrue(os.path.isdir(path))
+
+    def test_dry_run_keeps_everything(self):
+        self.assertEqual(sorted(self.trimmed(2, dry_run=True)), sorted(self.old))
+        for path in self.new + self.old:
+            self.assertTrue(os.path.isdir(path))
+
+    def test_min_events_kept_protects_the_newest_old_events(self):
+        self.assertEqual(sorted(self.trimmed(2, min_events_kept=3, dry_run=True)), sorted(self.old[1:]))
+
+    def test_max_events_kept_trims_newer_events(self):
+        self.assertEqual(sorted(self.trimmed(30, max_events_kept=2, dry_run=True)), sorted(self.old))
+        self.assertEqual(sorted(self.trimmed(30, max_events_kept=1, dry_run=True)), sorted(self.new[1:] + self.old))
+
+    def test_max_events_kept_applies_to_events_kept_by_min_events_kept(self):
+        self.assertEqual(sorted(self.trimmed(2, min_events_kept=3, max_events_kept=2, dry_run=True)), sorted(self.old))
+
+    def test_reasons(self):
+        reasons = dict(trim_events([self.tmp.name], 30, max_events_kept=4, dry_run=True))
+        self.assertEqual(list(reasons), [self.old[2]])
+        self.assertIn('newest 4', reasons[self.old[2]])
+
+class VerifyEventsTest(unittest.TestCase):
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def test_names_from_the_template_are_recognized(self):
+        open(os.path.join(self.tmp.name, 'Front_Door_2022-05-01T10-20-30.mp4'), 'w').close()
+        os.makedirs(os.path.join(self.tmp.name, 'Front_Door_2022-05-01T11-00-00_1', 'images'))
+        findings = dict(verify_events([self.tmp.name], True, name_template='{label}_%Y-%m-%dT%H-%M-%S', label='Front Door'))
+        self.assertEqual(findings[os.path.join(self.tmp.name, 'Front_Door_2022-05-01T10-20-30.mp4')], 'registered orphaned video')
+        self.assertEqual(findings[os.path.join(self.tmp.name, 'Front_Door_2022-05-01T11-00-00_1')], 'images without metadata')
+        metadata = Capture.load_metadata(os.path.join(self.tmp.name, 'Front_Door_2022-05-01T10-20-30'))
+        self.assertEqual(metadata['start'], datetime.datetime(2022, 5, 1, 10, 20, 30))
+
+    def test_other_names_are_unrecognized(self):
+        os.mkdir(os.path.join(self.tmp.name, 'holiday'))
+        self.assertEqual(verify_events([self.tmp.name], name_template='%Y-%m-%d'), [(os.path.join(self.tmp.name, 'holiday'), 'unrecognized directory')])
+
+class FakeCamera():
+
+    max_fps = 1
+
+    def add_callback(self, callback):
+        pass
+
+class CaptureQueueTest(unittest.TestCase):
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.spool = os.path.join(self.tmp.name, 'spool')
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def push(self, queue, timestamps, image=b'0123456789'):
+        for timestamp in timestamps:
+            with mock.patch('doorcapture.time.monotonic', return_value=timestamp):
+                queue.push(image)
+
+    def test_memory_only(self):
+        queue = CaptureQueue(FakeCamera(), 3)
+        self.push(queue, range(6))
+        self.assertEqual([x[0] for x in queue.queue], [2, 3, 4, 5])
+        self.assertEqual(queue.evicted, 2)
+
+    def test_older_frames_are_spilled_then_evicted_from_disk(self):
+        queue = CaptureQueue(FakeCamera(), 3, memory_time=1, spool_path=self.spool)
+        self.push(queue, range(6))
+        self.assertEqual([x[0] for x in queue.spooled], [2, 3])
+        self.assertEqual([x[0] for x in queue.queue], [4, 5])
+        self.assertEqual(sorted(os.listdir(self.spool)), ['2.000000.jpg', '3.000000.jpg'])
+        self.assertEqual(queue.evicted, 2)
+        self.assertEqual(queue.memory_bytes, 20)
+        stats = queue.stats()
+        self.assertEqual((stats['length'], stats['spooled'], stats['span']), (4, 2, 3))
+
+    def test_count_eviction_spans_memory_and_spool(self):
+        queue = CaptureQueue(FakeCamera(), 3, 'count', memory_time=1, spool_path=self.spool)
+        self.push(queue, range(6))
+        self.assertEqual(len(queue.spooled) + len(queue.queue), 3)
+        self.assertEqual([x[0] for x in queue.spooled], [3])
+        self.assertEqual(sorted(os.listdir(self.spool)), ['3.000000.jpg'])
+
+    def test_memory_budget_spills_to_disk(self):
+        queue = CaptureQueue(FakeCamera(), 100, memory_time=100, spool_path=self.spool, memory_budget=25)
+        self.push(queue, range(4))
+        self.assertEqual([x[0] for x in queue.spooled], [0])
+        self.assertEqual([x[0] for x in queue.queue], [1, 2, 3])
+        self.assertEqual(queue.evicted, 0)
+
+    def test_memory_budget_without_spool_evicts(self):
+        queue = CaptureQueue(FakeCamera(), 100, memory_budget=25)
+        self.push(queue, range(4))
+        self.assertEqual([x[0] for x in queue.queue], [1, 2, 3])
+        self.assertEqual(queue.evicted, 1)
+        self.assertEqual(queue.memory_bytes, 30)
+
+    def test_spool_is_cleared_on_start(self):
+        os.mkdir(self.spool)
+        open(os.path.join(self.spool, 'stale.jpg'), 'wb').close()
+        CaptureQueue(FakeCamera(), 3, memory_time=1, spool_path=self.spool)
+        self.assertEqual(os.listdir(self.spool), [])
+
+class RunPostProcessTest(unittest.TestCase):
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.capture = Capture.__new__(Capture)
+        self.capture.camera = FakeCamera()
+        self.capture.post_process_timeout = 30
+        for key in ('rotation', 'crop_region', 'timestamp_position', 'timestamp_format', 'timestamp_utc', 'timestamp_color', 'label', 'label_position', 'logo_path', 'logo_position'):
+            setattr(self.capture, key, None)
+        self.capture.video_encode = True
+        self.capture.keep_images = False
+        self.capture.container = 'mp4'
+        self.capture.timestamp = False
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def test_records_the_result_in_a_separate_process(self):
+        os.mkdir(os.path.join(self.tmp.name, 'images'))
+        self.assertTrue(self.capture.run_post_process(self.tmp.name))
+        self.assertEqual(Capture.load_metadata(self.tmp.name)['frames'], 0)
+
+    def test_reports_failures(self):
+        with self.assertLogs('doorcam.capture', 'ERROR'):
+            self.assertFalse(self.capture.run_post_process(self.tmp.name))
+        self.assertNotIn('frames', Capture.load_metadata(self.tmp.name))
+
+if __name__ == '__main__':
+    unittest.main()
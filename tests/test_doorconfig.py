@@ -0,0 +1,100 @@
+import unittest
+import datetime
+import tempfile
+import os
+from doorconfig import Config, profiles_to_windows, windows_overlap, validate_name_template, ImproperSchedule, ImproperNameTemplate
+
+def window(start, end):
+    return (datetime.time(*start), datetime.time(*end))
+
+class WindowsOverlapTest(unittest.TestCase):
+
+    def test_disjoint(self):
+        self.assertFalse(windows_overlap(window((8, 0), (12, 0)), window((12, 0), (18, 0))))
+
+    def test_overlapping(self):
+        self.assertTrue(windows_overlap(window((8, 0), (12, 1)), window((12, 0), (18, 0))))
+
+    def test_wrapping_past_midnight(self):
+        self.assertTrue(windows_overlap(window((22, 0), (6, 0)), window((5, 0), (7, 0))))
+        self.assertTrue(windows_overlap(window((22, 0), (6, 0)), window((23, 0), (2, 0))))
+        self.assertFalse(windows_overlap(window((22, 0), (6, 0)), window((6, 0), (22, 0))))
+
+class ProfilesToWindowsTest(unittest.TestCase):
+
+    def test_overrides_and_names(self):
+        windows = profiles_to_windows([
+            {'name': 'night', 'start': '22:00', 'end': '06:00', 'contour_minimum_area': 5000},
+            {'start': '12:00', 'end': '13:00', 'delta_threshold': 20}
+        ])
+        self.assertEqual(windows[0], {'name': 'night', 'start': datetime.time(22), 'end': datetime.time(6), 'contour_minimum_area': 5000})
+        self.assertEqual(windows[1]['name'], '12:00-13:00')
+        self.assertEqual(windows[1]['delta_threshold'], 20)
+        self.assertNotIn('contour_minimum_area', windows[1])
+
+    def test_rejects_overlapping_profiles(self):
+        with self.assertRaises(ImproperSchedule):
+            profiles_to_windows([
+                {'name': 'night', 'start': '22:00', 'end': '06:00'},
+                {'name': 'morning', 'start': '05:30', 'end': '09:00'}
+            ])
+
+    def test_adjacent_profiles_are_allowed(self):
+        self.assertEqual(len(profiles_to_windows([
+            {'name': 'night', 'start': '22:00', 'end': '06:00'},
+            {'name': 'day', 'start': '06:00', 'end': '22:00'}
+        ])), 2)
+
+    def test_rejects_empty_profiles(self):
+        with self.assertRaises(ImproperSchedule):
+            profiles_to_windows([{'start': '06:00', 'end': '06:00'}])
+
+class ValidateNameTemplateTest(unittest.TestCase):
+
+    def test_plain_names(self):
+        validate_name_template('%Y-%m-%d_%H-%M-%S-%f')
+        validate_name_template('{label}_%Y-%m-%dT%H-%M-%S', 'Front door')
+
+    def test_needs_strftime_fields(self):
+        with self.assertRaises(ImproperNameTemplate):
+            validate_name_template('{label}', 'front')
+
+    def test_rejects_path_separators(self):
+        with self.assertRaises(ImproperNameTemplate):
+            validate_name_template('%Y/%m/%d')
+        with self.assertRaises(ImproperNameTemplate):
+            validate_name_template('.%Y-%m-%d')
+
+    def test_rejects_labels_with_path_separators(self):
+        with self.assertRaises(ImproperNameTemplate):
+            validate_name_template('{label}_%Y-%m-%d', 'front/back')
+        with self.assertRaises(ImproperNameTemplate):
+            validate_name_template('{label}%Y-%m-%d', '..')
+
+    def test_label_is_ignored_when_not_in_the_template(self):
+        validate_name_template('%Y-%m-%d', 'front/back')
+
+class CameraConfigsTest(unittest.TestCase):
+
+    def load(self, text):
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        path = os.path.join(directory.name, 'config.yaml')
+        with open(path, 'w') as f:
+            f.write(text)
+        return Config(path, save=False)
+
+    def test_paths_are_siblings_of_the_main_camera(self):
+        config = self.load('cameras:\n  back:\n    camera:\n      index: 2\ncapture:\n  path: events/\n  fallback_path: /mnt/events\n')
+        back = config.camera_configs['back']
+        self.assertEqual(back['camera']['index'], 2)
+        self.assertEqual(back['capture']['path'], 'events_back')
+        self.assertEqual(back['capture']['fallback_path'], '/mnt/events_back')
+        self.assertEqual(config.capture_paths(), ['events/', '/mnt/events', 'events_back', '/mnt/events_back'])
+
+    def test_own_path_is_kept(self):
+        config = self.load('cameras:\n  back:\n    capture:\n      path: /srv/back\ncapture:\n  path: events\n')
+        self.assertEqual(config.capture_paths(), ['events', '/srv/back'])
+
+if __name__ == '__main__':
+    unittest.main()
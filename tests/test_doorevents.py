@@ -0,0 +1,66 @@
+import unittest
+import datetime
+from doorevents import parse_filter, filter_matches
+from doorconfig import ImproperTimeString
+
+def at(hour, minute=0):
+    return datetime.datetime(2024, 1, 1, hour, minute).timestamp()
+
+class ParseFilterTest(unittest.TestCase):
+
+    def test_empty_filter_matches_everything(self):
+        self.assertEqual(parse_filter({}), {})
+        self.assertTrue(filter_matches(parse_filter({}), 'motion_detected', at(12)))
+
+    def test_rejects_non_objects(self):
+        with self.assertRaises(ValueError):
+            parse_filter(['motion_detected'])
+
+    def test_types_must_be_a_list(self):
+        with self.assertRaises(ValueError):
+            parse_filter({'types': 'motion_detected'})
+
+    def test_window_needs_valid_times(self):
+        with self.assertRaises(ImproperTimeString):
+            parse_filter({'start': '22:00'})
+        with self.assertRaises(ImproperTimeString):
+            parse_filter({'start': '25:00', 'end': '06:00'})
+
+    def test_combinators_must_be_lists(self):
+        with self.assertRaises(ValueError):
+            parse_filter({'or': {'types': ['capture_error']}})
+
+class FilterMatchesTest(unittest.TestCase):
+
+    def test_types(self):
+        event_filter = parse_filter({'types': ['motion_detected', 'capture_started']})
+        self.assertTrue(filter_matches(event_filter, 'motion_detected', at(12)))
+        self.assertFalse(filter_matches(event_filter, 'camera_status', at(12)))
+
+    def test_window_wraps_past_midnight(self):
+        event_filter = parse_filter({'start': '22:00', 'end': '06:00'})
+        self.assertTrue(filter_matches(event_filter, 'motion_detected', at(23)))
+        self.assertTrue(filter_matches(event_filter, 'motion_detected', at(5, 59)))
+        self.assertFalse(filter_matches(event_filter, 'motion_detected', at(6)))
+        self.assertFalse(filter_matches(event_filter, 'motion_detected', at(12)))
+
+    def test_types_and_window_both_have_to_match(self):
+        event_filter = parse_filter({'types': ['motion_detected'], 'start': '22:00', 'end': '06:00'})
+        self.assertTrue(filter_matches(event_filter, 'motion_detected', at(23)))
+        self.assertFalse(filter_matches(event_filter, 'motion_detected', at(12)))
+        self.assertFalse(filter_matches(event_filter, 'capture_error', at(23)))
+
+    def test_or_and_not(self):
+        event_filter = parse_filter({'or': [{'types': ['capture_error']}, {'types': ['motion_detected'], 'not': {'start': '08:00', 'end': '18:00'}}]})
+        self.assertTrue(filter_matches(event_filter, 'capture_error', at(12)))
+        self.assertTrue(filter_matches(event_filter, 'motion_detected', at(20)))
+        self.assertFalse(filter_matches(event_filter, 'motion_detected', at(12)))
+        self.assertFalse(filter_matches(event_filter, 'camera_status', at(20)))
+
+    def test_and(self):
+        event_filter = parse_filter({'and': [{'types': ['motion_detected', 'motion_ended']}, {'types': ['motion_ended']}]})
+        self.assertTrue(filter_matches(event_filter, 'motion_ended', at(12)))
+        self.assertFalse(filter_matches(event_filter, 'motion_detected', at(12)))
+
+if __name__ == '__main__':
+    unittest.main()
@@ -0,0 +1,65 @@
+import unittest
+import tempfile
+import os
+from doorstream import HLSEncoder, parse_range
+
+class ParseRangeTest(unittest.TestCase):
+
+    def test_no_header(self):
+        self.assertIsNone(parse_range(None, 1000))
+
+    def test_other_units_and_multiple_ranges_are_ignored(self):
+        self.assertIsNone(parse_range('items=0-10', 1000))
+        self.assertIsNone(parse_range('bytes=0-10,20-30', 1000))
+
+    def test_closed_range(self):
+        self.assertEqual(parse_range('bytes=100-199', 1000), (100, 199))
+
+    def test_open_range(self):
+        self.assertEqual(parse_range('bytes=100-', 1000), (100, 999))
+
+    def test_end_is_clamped_to_size(self):
+        self.assertEqual(parse_range('bytes=900-5000', 1000), (900, 999))
+
+    def test_suffix_range(self):
+        self.assertEqual(parse_range('bytes=-100', 1000), (900, 999))
+        self.assertEqual(parse_range('bytes=-5000', 1000), (0, 999))
+
+    def test_reversed_range_is_ignored(self):
+        self.assertIsNone(parse_range('bytes=500-100', 1000))
+
+    def test_malformed_range_is_ignored(self):
+        self.assertIsNone(parse_range('bytes=a-b', 1000))
+        self.assertIsNone(parse_range('bytes=-', 1000))
+
+    def test_start_past_end_of_file_is_unsatisfiable(self):
+        self.assertIs(parse_range('bytes=1000-', 1000), False)
+        self.assertIs(parse_range('bytes=1500-2000', 1000), False)
+
+    def test_empty_suffix_is_unsatisfiable(self):
+        self.assertIs(parse_range('bytes=-0', 1000), False)
+
+class HLSEncoderClearTest(unittest.TestCase):
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.encoder = HLSEncoder.__new__(HLSEncoder)
+        self.encoder.path = os.path.join(self.tmp.name, 'hls')
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def test_creates_missing_directory(self):
+        self.encoder.clear()
+        self.assertTrue(os.path.isdir(self.encoder.path))
+
+    def test_removes_only_hls_files(self):
+        os.mkdir(self.encoder.path)
+        os.mkdir(os.path.join(self.encoder.path, 'events'))
+        for filename in ('playlist.m3u8', 'playlist.m3u8.tmp', 'segment_00001.ts', 'segment_12345.ts', 'notes.txt', 'segment_old.ts'):
+            open(os.path.join(self.encoder.path, filename), 'w').close()
+        self.encoder.clear()
+        self.assertEqual(sorted(os.listdir(self.encoder.path)), ['events', 'notes.txt', 'segment_old.ts'])
+
+if __name__ == '__main__':
+    unittest.main()
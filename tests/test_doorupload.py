@@ -0,0 +1,74 @@
+import unittest
+import tempfile
+import os
+from unittest import mock
+from doorcapture import Capture
+from doorupload import Uploader
+
+class UploaderTest(unittest.TestCase):
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.event = os.path.join(self.tmp.name, '2024-01-01_12-00-00-000000')
+        os.mkdir(self.event)
+        with mock.patch('doorupload.Thread'):
+            self.uploader = Uploader([self.tmp.name], 'http://localhost/events', delete_local=True)
+        self.uploaded = []
+        self.uploader.put = lambda url, filepath: self.uploaded.append(url)
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def write(self, filename):
+        os.makedirs(os.path.dirname(os.path.join(self.event, filename)), exist_ok=True)
+        with open(os.path.join(self.event, filename), 'wb') as out:
+            out.write(b'data')
+
+    def test_uploads_the_video(self):
+        self.write('event.mp4')
+        self.write('images/2024-01-01_12-00-00-100000.jpg')
+        Capture.update_metadata(self.event, {'video': 'event.mp4'})
+        self.uploader.upload(self.event)
+        self.assertEqual(self.uploaded, [
+            'http://localhost/events/2024-01-01_12-00-00-000000/event.mp4',
+            'http://localhost/events/2024-01-01_12-00-00-000000/metadata.yaml'
+        ])
+        self.assertFalse(os.path.exists(self.event))
+
+    def test_uploads_images_without_a_video(self):
+        self.write('images/2024-01-01_12-00-00-200000.jpg')
+        self.write('images/2024-01-01_12-00-00-100000.jpg')
+        Capture.update_metadata(self.event, {'video': None})
+        self.uploader.upload(self.event)
+        self.assertEqual(self.uploaded, [
+            'http://localhost/events/2024-01-01_12-00-00-000000/images/2024-01-01_12-00-00-100000.jpg',
+            'http://localhost/events/2024-01-01_12-00-00-000000/images/2024-01-01_12-00-00-200000.jpg',
+            'http://localhost/events/2024-01-01_12-00-00-000000/metadata.yaml'
+        ])
+        self.assertFalse(os.path.exists(self.event))
+
+    def test_keeps_events_without_media(self):
+        Capture.update_metadata(self.event, {'video': None})
+        self.uploader.upload(self.event)
+        self.assertEqual(self.uploaded, ['http://localhost/events/2024-01-01_12-00-00-000000/metadata.yaml'])
+        self.assertTrue(os.path.isdir(self.event))
+
+    def test_failed_upload_moves_to_the_back_of_the_queue(self):
+        other = os.path.join(self.tmp.name, '2024-01-01_13-00-00-000000')
+        os.mkdir(other)
+        attempts = []
+        def upload(path):
+            attempts.append(path)
+            if path == self.event:
+                raise OSError('rejected')
+            raise SystemExit
+        self.uploader.upload = upload
+        self.uploader.queue = [self.event, other]
+        with mock.patch('doorupload.time.sleep'):
+            with self.assertRaises(SystemExit):
+                self.uploader.upload_loop()
+        self.assertEqual(attempts, [self.event, other])
+        self.assertEqual(self.uploader.queue, [other, self.event])
+
+if __name__ == '__main__':
+    unittest.main()